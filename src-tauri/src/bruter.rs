@@ -0,0 +1,252 @@
+use std::{sync::Arc, time::Instant};
+
+use futures::{StreamExt, stream};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::{AppState, client::{SendError, Target}, criteria::{Criteria, Observed, Outcome}, network::store_response, repeater::{send_parsed, tagged}};
+
+const MARKER: char = '§';
+const DEFAULT_CONCURRENCY: usize = 20;
+/// Most requests one attack may send
+const MAX_ATTEMPTS: usize = 100_000;
+
+/// A raw request with `§marker§` payload positions. The text between a pair of markers is the
+/// position's default value
+#[derive(Debug, Clone)]
+pub struct Template {
+    /// Literal text around the positions, one more than there are positions
+    literals: Vec<String>,
+    defaults: Vec<String>,
+}
+
+impl Template {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let segments = raw.split(MARKER).map(str::to_string).collect::<Vec<String>>();
+        if segments.len() % 2 == 0 {
+            return Err(format!("Unpaired {MARKER} marker in template"))
+        }
+
+        let (literals, defaults) = segments.into_iter().enumerate().fold((Vec::new(), Vec::new()), |(mut literals, mut defaults), (i, segment)| {
+            if i % 2 == 0 { literals.push(segment) } else { defaults.push(segment) }
+            (literals, defaults)
+        });
+        Ok(Template { literals, defaults })
+    }
+
+    pub fn positions(&self) -> usize {
+        self.defaults.len()
+    }
+
+    /// The request with a payload in each position, or the default where it's `None`
+    pub fn render(&self, payloads: &[Option<&str>]) -> String {
+        let mut raw = self.literals[0].clone();
+        for (i, literal) in self.literals[1..].iter().enumerate() {
+            raw.push_str(payloads.get(i).copied().flatten().unwrap_or(&self.defaults[i]));
+            raw.push_str(literal);
+        }
+        raw
+    }
+}
+
+/// How payload lists are placed into the template's positions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttackMode {
+    /// Each position in turn gets every payload of its list while the others keep their defaults
+    Sniper,
+    /// The same payload in every position, from the first list
+    BatteringRam,
+    /// The nth payload of every list together, stopping at the shortest list
+    Pitchfork,
+    /// Every combination of the lists
+    #[default]
+    ClusterBomb,
+}
+
+impl AttackMode {
+    /// Number of requests the attack sends, `None` when it doesn't fit in a `usize`
+    pub fn total(&self, lists: &[Vec<String>]) -> Option<usize> {
+        if lists.is_empty() {
+            return Some(0)
+        }
+        match self {
+            AttackMode::Sniper => lists.iter().map(Vec::len).try_fold(0usize, usize::checked_add),
+            AttackMode::BatteringRam => Some(lists[0].len()),
+            AttackMode::Pitchfork => lists.iter().map(Vec::len).min(),
+            AttackMode::ClusterBomb => lists.iter().map(Vec::len).try_fold(1usize, usize::checked_mul),
+        }
+    }
+
+    /// Payload of every position for the `index`th request
+    pub fn payloads<'a>(&self, lists: &'a [Vec<String>], mut index: usize) -> Vec<Option<&'a str>> {
+        let mut payloads = vec![None; lists.len()];
+        match self {
+            AttackMode::Sniper => {
+                for (position, list) in lists.iter().enumerate() {
+                    if index < list.len() {
+                        payloads[position] = Some(list[index].as_str());
+                        break
+                    }
+                    index -= list.len();
+                }
+            },
+            AttackMode::BatteringRam => payloads.fill(Some(lists[0][index].as_str())),
+            AttackMode::Pitchfork => {
+                for (position, list) in lists.iter().enumerate() {
+                    payloads[position] = Some(list[index].as_str());
+                }
+            },
+            // Counts through the lists like an odometer, the last position turning fastest
+            AttackMode::ClusterBomb => {
+                for (position, list) in lists.iter().enumerate().rev() {
+                    payloads[position] = Some(list[index % list.len()].as_str());
+                    index /= list.len();
+                }
+            },
+        }
+        payloads
+    }
+}
+
+/// Outcome of one request of a template attack
+#[derive(Debug, Clone, Serialize)]
+pub struct Attempt {
+    pub index: usize,
+    /// Payload per position, `None` where the default was kept
+    pub payloads: Vec<Option<String>>,
+    pub status: Option<u16>,
+    /// Response size in bytes
+    pub length: usize,
+    pub time_ms: u128,
+    /// Id of the stored response
+    pub id: Option<String>,
+    pub error: Option<SendError>,
+    /// `Unknown` when the request failed
+    pub outcome: Outcome,
+}
+
+/// What every request of one attack shares
+struct Attack {
+    template: Template,
+    lists: Vec<Vec<String>>,
+    mode: AttackMode,
+    target: Option<Target>,
+    use_cookie_jar: bool,
+    criteria: Criteria,
+}
+
+async fn attempt(state: &AppState, attack: &Attack, index: usize, baseline_ms: Option<u128>) -> Attempt {
+    let payloads = attack.mode.payloads(&attack.lists, index);
+    let raw = attack.template.render(&payloads);
+    let started = Instant::now();
+    let result = tagged(state, None, None, send_parsed(state, &raw, attack.target.clone(), None, 0, attack.use_cookie_jar)).await;
+
+    let mut attempt = Attempt {
+        index,
+        payloads: payloads.iter().map(|payload| payload.map(str::to_string)).collect(),
+        status: None,
+        length: 0,
+        time_ms: started.elapsed().as_millis(),
+        id: None,
+        error: None,
+        outcome: Outcome::Unknown,
+    };
+    match result {
+        Ok(res) => {
+            attempt.status = res.status.split_whitespace().next().and_then(|status| status.parse().ok());
+            attempt.length = res.raw.len();
+            attempt.outcome = attack.criteria.outcome(&Observed { status: attempt.status.unwrap_or(0), raw: &res.raw, time_ms: attempt.time_ms }, baseline_ms);
+            attempt.id = Some(store_response(state, res.raw).await);
+        },
+        Err(e) => attempt.error = Some(e.error),
+    }
+    attempt
+}
+
+/// Time of the template sent with its defaults
+async fn baseline(state: &AppState, attack: &Attack) -> Option<u128> {
+    let started = Instant::now();
+    match tagged(state, None, None, send_parsed(state, &attack.template.render(&[]), attack.target.clone(), None, 0, attack.use_cookie_jar)).await {
+        Ok(_) => {
+            let baseline_ms = started.elapsed().as_millis();
+            info!("Baseline request took {baseline_ms}ms");
+            Some(baseline_ms)
+        },
+        Err(e) => {
+            error!("Baseline request failed, timing matchers will not match: {}", e.error);
+            None
+        },
+    }
+}
+
+fn read_lines(path: &str) -> Result<Vec<String>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Failed to read payloads {path}: {e}"))?;
+    Ok(contents.lines().map(str::to_string).filter(|line| !line.is_empty()).collect())
+}
+
+/// How a template attack is sent, apart from the template and its payloads
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AttackOptions {
+    /// Where to connect, the template's Host header when not given
+    #[serde(default)]
+    pub target: Option<Target>,
+    #[serde(default)]
+    pub mode: AttackMode,
+    #[serde(default)]
+    pub criteria: Criteria,
+    #[serde(default)]
+    pub use_cookie_jar: bool,
+    /// Requests in flight at once
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+}
+
+/// Sends the template with payloads from `payload_files`, one list per position or a single list
+/// used for all of them, placed according to the mode in `options`. Returns the number of requests the attack sends.
+/// Each attempt is emitted as `bruter-attempt` with its outcome under the criteria and `bruter-finished` follows the last.
+/// When a matcher compares timings the template with its defaults is sent first as the baseline
+#[tauri::command]
+pub async fn bruteforce_template(template: String, payload_files: Vec<String>, options: Option<AttackOptions>, app_handle: AppHandle) -> Result<usize, String> {
+    let AttackOptions { target, mode, criteria, use_cookie_jar, concurrency } = options.unwrap_or_default();
+    let template = Template::parse(&template)?;
+    let criteria = criteria.compile()?;
+    let mut lists = payload_files.iter().map(|path| read_lines(path)).collect::<Result<Vec<Vec<String>>, String>>()?;
+    if lists.len() == 1 {
+        lists = vec![lists[0].clone(); template.positions()];
+    }
+    if lists.len() != template.positions() {
+        return Err(format!("Template has {} positions but {} payload lists were given", template.positions(), lists.len()))
+    }
+    let total = mode.total(&lists)
+        .filter(|total| *total <= MAX_ATTEMPTS)
+        .ok_or(format!("{mode:?} attack would send more than {MAX_ATTEMPTS} requests"))?;
+
+    info!("Starting {mode:?} bruteforce: {total} requests over {} positions", template.positions());
+    let state = app_handle.state::<Arc<AppState>>().inner().clone();
+    let concurrency = concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1);
+    let attack = Attack { template, lists, mode, target, use_cookie_jar, criteria };
+
+    tauri::async_runtime::spawn(async move {
+        let baseline_ms = match attack.criteria.needs_baseline() {
+            true => baseline(&state, &attack).await,
+            false => None,
+        };
+
+        stream::iter(0..total)
+            .for_each_concurrent(concurrency, |index| {
+                let (state, attack, app_handle) = (&state, &attack, &app_handle);
+                async move {
+                    let attempt = attempt(state, attack, index, baseline_ms).await;
+                    if let Err(e) = app_handle.emit("bruter-attempt", attempt) {
+                        error!("Failed to emit Bruter attempt: {e}");
+                    }
+                }
+            })
+            .await;
+        let _ = app_handle.emit("bruter-finished", total);
+    });
+
+    Ok(total)
+}
@@ -0,0 +1,233 @@
+use std::{error::Error, fmt, io, sync::Arc, time::{Duration, Instant}};
+
+use http_body_util::{BodyExt, Full};
+use hyper::{HeaderMap, Method, Request, Response, body::Bytes, client::conn::http1};
+use hyper_util::rt::TokioIo;
+use log::error;
+use serde::{Deserialize, Serialize};
+use tauri::http::{HeaderName, HeaderValue};
+use tokio::{io::{AsyncRead, AsyncWrite}, net::{TcpStream, lookup_host}, time::timeout};
+use tokio_rustls::{TlsConnector, rustls::{ClientConfig, pki_types::ServerName}};
+
+use crate::{AppState, handshake::UpstreamTls, tls};
+
+/// Longest each of DNS, connecting and the TLS handshake may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest to wait for the response head, and then for each chunk of its body
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Where a request failed, so tools can tell an unreachable host from a bad certificate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "message", rename_all = "snake_case")]
+pub enum SendError {
+    InvalidRequest(String),
+    Dns(String),
+    Connect(String),
+    Tls(String),
+    Timeout(String),
+    Http(String),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::InvalidRequest(msg) => write!(f, "Invalid request: {msg}"),
+            SendError::Dns(msg) => write!(f, "DNS error: {msg}"),
+            SendError::Connect(msg) => write!(f, "Connection error: {msg}"),
+            SendError::Tls(msg) => write!(f, "TLS error: {msg}"),
+            SendError::Timeout(msg) => write!(f, "Timed out: {msg}"),
+            SendError::Http(msg) => write!(f, "HTTP error: {msg}"),
+        }
+    }
+}
+
+impl Error for SendError {}
+
+impl From<SendError> for io::Error {
+    fn from(e: SendError) -> Self {
+        let kind = match e {
+            SendError::InvalidRequest(_) => io::ErrorKind::InvalidData,
+            SendError::Timeout(_) => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e.to_string())
+    }
+}
+
+/// Milliseconds spent in each phase of a request. DNS is left out when an upstream proxy resolves the host
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Timings {
+    pub dns_ms: Option<u128>,
+    pub connect_ms: u128,
+    pub tls_ms: Option<u128>,
+    pub ttfb_ms: u128,
+    pub total_ms: u128,
+}
+
+/// Where a request is sent, independent of its Host header
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Target {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    /// Authority as written in a Host header, leaving out the default port
+    pub fn authority(&self) -> String {
+        let host = if self.host.contains(":") { format!("[{}]", self.host) } else { self.host.clone() };
+        match (self.scheme.as_str(), self.port) {
+            ("https", 443) | ("http", 80) => host,
+            (_, port) => format!("{host}:{port}"),
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}://{}{}", self.scheme, self.authority(), path)
+    }
+}
+
+/// Connection to an upstream server that we drive ourselves, so the TLS handshake can be inspected
+pub struct Connection {
+    pub stream: Box<dyn Stream>,
+    pub tls: Option<UpstreamTls>,
+    pub timings: Timings,
+}
+
+/// Connects to the target through the configured upstream proxy and, for https,
+/// performs the handshake with the TLS profile matching the host. `sni` takes precedence over the profile's
+pub async fn connect(state: &AppState, target: &Target, sni: Option<&str>) -> Result<Connection, SendError> {
+    connect_with_alpn(state, target, sni, &[b"http/1.1"]).await
+}
+
+/// [`connect`] offering the given ALPN protocols instead of only HTTP/1.1
+pub async fn connect_with_alpn(state: &AppState, target: &Target, sni: Option<&str>, alpn: &[&[u8]]) -> Result<Connection, SendError> {
+    let (scheme, host, port) = (target.scheme.as_str(), target.host.as_str(), target.port);
+    let mut timings = Timings::default();
+    let upstream = state.upstream.lock().await.clone();
+
+    let started = Instant::now();
+    let stream = if upstream.select(host).is_none() {
+        let addrs = timeout(CONNECT_TIMEOUT, lookup_host((host, port))).await
+            .map_err(|_| SendError::Timeout(format!("Resolving {host}")))?
+            .map_err(|e| SendError::Dns(format!("Failed to resolve {host}: {e}")))?
+            .collect::<Vec<_>>();
+        timings.dns_ms = Some(started.elapsed().as_millis());
+
+        let connect_started = Instant::now();
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(&addrs[..])).await
+            .map_err(|_| SendError::Timeout(format!("Connecting to {host}:{port}")))?
+            .map_err(|e| SendError::Connect(format!("Failed to connect to {host}:{port}: {e}")))?;
+        timings.connect_ms = connect_started.elapsed().as_millis();
+        stream
+    } else {
+        let stream = timeout(CONNECT_TIMEOUT, upstream.connect(host, port)).await
+            .map_err(|_| SendError::Timeout(format!("Connecting to {host}:{port} through upstream")))?
+            .map_err(|e| SendError::Connect(format!("Failed to connect to {host}:{port} through upstream: {e}")))?;
+        timings.connect_ms = started.elapsed().as_millis();
+        stream
+    };
+
+    if scheme != "https" {
+        return Ok(Connection { stream: Box::new(stream), tls: None, timings })
+    }
+
+    let profiles = state.tls_profiles.lock().await.clone();
+    let profile = tls::select_profile(&profiles, host);
+    let mut config = ClientConfig::clone(&*tls::profile_config(profile).map_err(|e| SendError::Tls(e.to_string()))?);
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+    let sni = sni.map(str::to_string).or(profile.and_then(|profile| profile.sni.clone())).unwrap_or(host.to_string());
+    let server_name = ServerName::try_from(sni.clone())
+        .map_err(|e| SendError::Tls(format!("Invalid server name {sni}: {e}")))?;
+
+    let tls_started = Instant::now();
+    let tls_stream = timeout(CONNECT_TIMEOUT, TlsConnector::from(Arc::new(config)).connect(server_name, stream)).await
+        .map_err(|_| SendError::Timeout(format!("TLS handshake with {host}:{port}")))?
+        .map_err(|e| SendError::Tls(format!("Handshake with {host}:{port} failed: {e}")))?;
+    timings.tls_ms = Some(tls_started.elapsed().as_millis());
+    let tls = UpstreamTls::from_connection(tls_stream.get_ref().1);
+
+    Ok(Connection { stream: Box::new(tls_stream), tls: Some(tls), timings })
+}
+
+/// Parses a raw HTTP/1.1 request into a request hyper can send, returning it with its Host header.
+/// Content framing is dropped since the body may have been edited, hyper frames it again
+pub fn build_request(raw: &str) -> Result<(Request<Full<Bytes>>, Option<String>), SendError> {
+    let Some((headers_str, body)) = raw.split_once("\r\n\r\n") else {
+        return Err(SendError::InvalidRequest("Missing blank line after headers".to_string()))
+    };
+
+    let Some(request_line) = headers_str.lines().next() else {
+        return Err(SendError::InvalidRequest("Malformed request data".to_string()))
+    };
+    let (method, path, _version) = match request_line.split_whitespace().collect::<Vec<&str>>()[..] {
+        [m, p, v] => (m, p, v),
+        _ => return Err(SendError::InvalidRequest(format!("Malformed request line: {request_line}"))),
+    };
+
+    let mut headers = HeaderMap::new();
+    let mut host = None;
+    for header in headers_str.lines().skip(1) {
+        if let Some((key, value)) = header.split_once(":") {
+            if key.trim().eq_ignore_ascii_case("host") {
+                host = Some(value.trim().to_string());
+            }
+
+            let hname = HeaderName::from_bytes(key.trim().as_bytes())
+                .map_err(|e| SendError::InvalidRequest(format!("Invalid header data: {e}")))?;
+            let hvalue = HeaderValue::from_str(value.trim())
+                .map_err(|e| SendError::InvalidRequest(format!("Invalid header data: {e}")))?;
+            headers.append(hname, hvalue);
+        }
+    }
+
+    headers.remove("accept-encoding");
+    headers.remove("content-length");
+    headers.remove("transfer-encoding");
+
+    let method = Method::from_bytes(method.as_bytes())
+        .map_err(|e| SendError::InvalidRequest(format!("Invalid request method: {e}")))?;
+    let mut req = Request::builder()
+        .method(method)
+        .uri(path)
+        .body(Full::new(Bytes::from(body.to_string())))
+        .map_err(|e| SendError::InvalidRequest(e.to_string()))?;
+    *req.headers_mut() = headers;
+
+    Ok((req, host))
+}
+
+/// Sends a single HTTP/1.1 request over the connection and reads the whole response, giving up when
+/// the server goes quiet for `READ_TIMEOUT`. Also returns the time to the first byte of the response
+pub async fn send(stream: Box<dyn Stream>, request: Request<Full<Bytes>>) -> Result<(Response<Bytes>, Duration), SendError> {
+    let started = Instant::now();
+    let (mut sender, conn) = http1::handshake(TokioIo::new(stream)).await
+        .map_err(|e| SendError::Http(format!("HTTP handshake failed: {e}")))?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            error!("Upstream connection error: {e}");
+        }
+    });
+
+    let res = timeout(READ_TIMEOUT, sender.send_request(request)).await
+        .map_err(|_| SendError::Timeout("Waiting for the response".to_string()))?
+        .map_err(|e| SendError::Http(format!("Failed sending request to server: {e}")))?;
+    let ttfb = started.elapsed();
+
+    let (parts, mut body) = res.into_parts();
+    let mut collected = Vec::new();
+    while let Some(frame) = timeout(READ_TIMEOUT, body.frame()).await
+        .map_err(|_| SendError::Timeout("Reading the response body".to_string()))? {
+        let frame = frame.map_err(|e| SendError::Http(format!("Failed reading response body: {e}")))?;
+        if let Some(data) = frame.data_ref() {
+            collected.extend_from_slice(data);
+        }
+    }
+    let body = Bytes::from(collected);
+
+    Ok((Response::from_parts(parts, body), ttfb))
+}
@@ -0,0 +1,417 @@
+use std::collections::VecDeque;
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::{client::{self, Target}, repeater::target_from_host};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    Curl,
+    /// Python requests
+    Python,
+    /// JavaScript fetch
+    JavaScript,
+    /// Go net/http
+    Go,
+    /// Rust reqwest
+    Rust,
+}
+
+/// A raw request broken into the parts every snippet needs
+struct Parts {
+    method: String,
+    url: String,
+    /// Headers other than framing and a Host header matching the URL
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+/// Parses the request the same way it's forwarded, with the URL from `target` or the Host header
+fn parts(raw: &str, target: Option<Target>) -> Result<Parts, String> {
+    let (req, host) = client::build_request(raw).map_err(|e| e.to_string())?;
+    let target = match target {
+        Some(target) => target,
+        None => target_from_host(host).map_err(|e| e.to_string())?,
+    };
+    let authority = target.authority();
+
+    let headers = req.headers().iter()
+        .filter(|(name, value)| !(name.as_str() == "host" && value.to_str().is_ok_and(|host| host.eq_ignore_ascii_case(&authority))))
+        .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).to_string()))
+        .collect();
+    let body = raw.split_once("\r\n\r\n").map(|(_, body)| body).unwrap_or("").to_string();
+
+    Ok(Parts {
+        method: req.method().to_string(),
+        url: target.url(&req.uri().to_string()),
+        headers,
+        body,
+    })
+}
+
+/// Single-quoted for POSIX shells
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace("'", r"'\''"))
+}
+
+/// Double-quoted string literal valid in Python, JavaScript and Go
+fn quote(text: &str) -> String {
+    serde_json::to_string(text).unwrap_or_default()
+}
+
+fn rust_quote(text: &str) -> String {
+    let escaped = text.chars().map(|c| match c {
+        '"' => "\\\"".to_string(),
+        '\\' => "\\\\".to_string(),
+        '\n' => "\\n".to_string(),
+        '\r' => "\\r".to_string(),
+        '\t' => "\\t".to_string(),
+        c if c.is_control() => format!("\\u{{{:x}}}", c as u32),
+        c => c.to_string(),
+    }).collect::<String>();
+    format!("\"{escaped}\"")
+}
+
+fn curl(parts: &Parts) -> String {
+    let mut args = vec![format!("curl {}", shell_quote(&parts.url))];
+    let implied = if parts.body.is_empty() { "GET" } else { "POST" };
+    if parts.method != implied {
+        args.push(format!("-X {}", shell_quote(&parts.method)));
+    }
+    for (name, value) in &parts.headers {
+        args.push(format!("-H {}", shell_quote(&format!("{name}: {value}"))));
+    }
+    if !parts.body.is_empty() {
+        args.push(format!("--data-raw {}", shell_quote(&parts.body)));
+    }
+    args.join(" \\\n  ")
+}
+
+fn python(parts: &Parts) -> String {
+    let mut code = format!("import requests\n\nresponse = requests.request(\n    {},\n    {},\n", quote(&parts.method), quote(&parts.url));
+    if !parts.headers.is_empty() {
+        code.push_str("    headers={\n");
+        for (name, value) in &parts.headers {
+            code.push_str(&format!("        {}: {},\n", quote(name), quote(value)));
+        }
+        code.push_str("    },\n");
+    }
+    if !parts.body.is_empty() {
+        code.push_str(&format!("    data={},\n", quote(&parts.body)));
+    }
+    code.push_str(")\nprint(response.status_code)\nprint(response.text)\n");
+    code
+}
+
+fn javascript(parts: &Parts) -> String {
+    let mut code = format!("const response = await fetch({}, {{\n    method: {},\n", quote(&parts.url), quote(&parts.method));
+    if !parts.headers.is_empty() {
+        code.push_str("    headers: {\n");
+        for (name, value) in &parts.headers {
+            code.push_str(&format!("        {}: {},\n", quote(name), quote(value)));
+        }
+        code.push_str("    },\n");
+    }
+    if !parts.body.is_empty() {
+        code.push_str(&format!("    body: {},\n", quote(&parts.body)));
+    }
+    code.push_str("});\nconsole.log(response.status);\nconsole.log(await response.text());\n");
+    code
+}
+
+fn go(parts: &Parts) -> String {
+    let has_body = !parts.body.is_empty();
+    let mut code = String::from("package main\n\nimport (\n\t\"fmt\"\n\t\"io\"\n\t\"net/http\"\n");
+    if has_body {
+        code.push_str("\t\"strings\"\n");
+    }
+    code.push_str(")\n\nfunc main() {\n");
+
+    let body = if has_body {
+        code.push_str(&format!("\tbody := strings.NewReader({})\n", quote(&parts.body)));
+        "body"
+    } else {
+        "nil"
+    };
+    code.push_str(&format!("\treq, err := http.NewRequest({}, {}, {body})\n\tif err != nil {{\n\t\tpanic(err)\n\t}}\n", quote(&parts.method), quote(&parts.url)));
+    for (name, value) in &parts.headers {
+        // Go sends the Host header from req.Host and ignores it in req.Header
+        match name.as_str() {
+            "host" => code.push_str(&format!("\treq.Host = {}\n", quote(value))),
+            _ => code.push_str(&format!("\treq.Header.Add({}, {})\n", quote(name), quote(value))),
+        }
+    }
+
+    code.push_str("\n\tresp, err := http.DefaultClient.Do(req)\n\tif err != nil {\n\t\tpanic(err)\n\t}\n\tdefer resp.Body.Close()\n\n");
+    code.push_str("\tdata, err := io.ReadAll(resp.Body)\n\tif err != nil {\n\t\tpanic(err)\n\t}\n\tfmt.Println(resp.Status)\n\tfmt.Println(string(data))\n}\n");
+    code
+}
+
+fn rust(parts: &Parts) -> String {
+    let method = match parts.method.as_str() {
+        "GET" | "POST" | "PUT" | "DELETE" | "HEAD" | "OPTIONS" | "CONNECT" | "PATCH" | "TRACE" => format!("reqwest::Method::{}", parts.method),
+        method => format!("reqwest::Method::from_bytes(b{}).unwrap()", rust_quote(method)),
+    };
+
+    let mut code = format!("#[tokio::main]\nasync fn main() -> Result<(), reqwest::Error> {{\n    let response = reqwest::Client::new()\n        .request({method}, {})\n", rust_quote(&parts.url));
+    for (name, value) in &parts.headers {
+        code.push_str(&format!("        .header({}, {})\n", rust_quote(name), rust_quote(value)));
+    }
+    if !parts.body.is_empty() {
+        code.push_str(&format!("        .body({})\n", rust_quote(&parts.body)));
+    }
+    code.push_str("        .send()\n        .await?;\n\n    println!(\"{}\", response.status());\n    println!(\"{}\", response.text().await?);\n    Ok(())\n}\n");
+    code
+}
+
+/// Snippet sending the raw request in the given language
+pub fn generate(raw: &str, language: Language, target: Option<Target>) -> Result<String, String> {
+    let parts = parts(raw, target)?;
+    Ok(match language {
+        Language::Curl => curl(&parts),
+        Language::Python => python(&parts),
+        Language::JavaScript => javascript(&parts),
+        Language::Go => go(&parts),
+        Language::Rust => rust(&parts),
+    })
+}
+
+/// Splits a command line the way a POSIX shell would, joining backslash-continued lines
+fn shell_words(cmd: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = cmd.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("Unterminated single quote".to_string()),
+                    }
+                }
+            },
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some('\n') => {},
+                            Some(c) => { word.push('\\'); word.push(c); },
+                            None => return Err("Unterminated double quote".to_string()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("Unterminated double quote".to_string()),
+                    }
+                }
+            },
+            // ANSI-C quoting, as used by browsers' "Copy as cURL"
+            '$' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => word.push('\n'),
+                            Some('r') => word.push('\r'),
+                            Some('t') => word.push('\t'),
+                            Some(c) => word.push(c),
+                            None => return Err("Unterminated quote".to_string()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("Unterminated quote".to_string()),
+                    }
+                }
+            },
+            '\\' => match chars.next() {
+                Some('\n') | Some('\r') => {},
+                Some(c) => { in_word = true; word.push(c); },
+                None => {},
+            },
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            },
+            c => { in_word = true; word.push(c); },
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+/// A request imported from curl, with where it was sent
+#[derive(Debug, Serialize)]
+pub struct CurlImport {
+    pub raw: String,
+    pub target: Target,
+}
+
+/// Options whose value is skipped since it doesn't change the request
+const SKIPPED_WITH_VALUE: [&str; 12] = ["-o", "--output", "-m", "--max-time", "--connect-timeout", "-x", "--proxy", "-w", "--write-out", "--retry", "-c", "--cookie-jar"];
+
+/// Short options that take a value, which may be attached as in `-XPOST`
+const SHORT_WITH_VALUE: [char; 12] = ['X', 'H', 'd', 'u', 'b', 'A', 'e', 'o', 'm', 'x', 'w', 'c'];
+
+/// Percent-encodes everything but unreserved characters, as curl's `--data-urlencode` does
+fn url_encode(text: &str) -> String {
+    text.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        b => format!("%{b:02X}"),
+    }).collect()
+}
+
+/// `--data-urlencode` value: `content` and `=content` are encoded whole, `name=content` keeps the name.
+/// Without a `=`, an `@` makes curl read the content from a file
+fn data_urlencode(value: &str) -> Result<String, String> {
+    if !value.contains('=') && value.contains('@') {
+        return Err(format!("--data-urlencode {value} reads a file, which can't be imported"))
+    }
+    Ok(match value.split_once('=') {
+        Some(("", content)) => url_encode(content),
+        Some((name, content)) => format!("{name}={}", url_encode(content)),
+        None => url_encode(value),
+    })
+}
+
+/// Raw HTTP/1.1 request from a curl command line
+pub fn parse_curl(cmd: &str) -> Result<CurlImport, String> {
+    let words = shell_words(cmd.trim())?;
+    let mut args = words.into_iter().collect::<VecDeque<String>>();
+    if args.pop_front().as_deref() != Some("curl") {
+        return Err("Not a curl command".to_string())
+    }
+
+    let mut method = None;
+    let mut url = None;
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut data = Vec::new();
+    let mut get = false;
+
+    while let Some(arg) = args.pop_front() {
+        let mut value = || args.pop_front().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "-X" | "--request" => method = Some(value()?),
+            "-H" | "--header" => {
+                let header = value()?;
+                if let Some((name, value)) = header.split_once(":") {
+                    headers.push((name.trim().to_string(), value.trim().to_string()));
+                }
+            },
+            "-d" | "--data" | "--data-raw" | "--data-binary" | "--data-ascii" => data.push(value()?),
+            "--data-urlencode" => data.push(data_urlencode(&value()?)?),
+            "-u" | "--user" => headers.push(("Authorization".to_string(), format!("Basic {}", BASE64_STANDARD.encode(value()?)))),
+            "-b" | "--cookie" => headers.push(("Cookie".to_string(), value()?)),
+            "-A" | "--user-agent" => headers.push(("User-Agent".to_string(), value()?)),
+            "-e" | "--referer" => headers.push(("Referer".to_string(), value()?)),
+            "-I" | "--head" => method = Some("HEAD".to_string()),
+            "-G" | "--get" => get = true,
+            "--url" => url = Some(value()?),
+            flag if SKIPPED_WITH_VALUE.contains(&flag) => { value()?; },
+            // Combined short options, `-sSL` or `-XPOST`, are split and parsed one at a time
+            flag if flag.len() > 2 && flag.starts_with('-') && !flag.starts_with("--") => {
+                let mut chars = flag[1..].chars();
+                let first = chars.next().unwrap_or_default();
+                let rest = match SHORT_WITH_VALUE.contains(&first) {
+                    true => chars.as_str().to_string(),
+                    false => format!("-{}", chars.as_str()),
+                };
+                args.push_front(rest);
+                args.push_front(format!("-{first}"));
+            },
+            flag if flag.starts_with('-') => {},
+            _ => url = Some(arg),
+        }
+    }
+
+    let url = url.ok_or("No URL in curl command".to_string())?;
+    let url = if url.contains("://") { url } else { format!("http://{url}") };
+    let mut url = Url::parse(&url).map_err(|e| format!("Invalid URL {url}: {e}"))?;
+    let target = Target {
+        scheme: url.scheme().to_string(),
+        host: url.host_str().ok_or(format!("No host in {url}"))?.trim_matches(['[', ']']).to_string(),
+        port: url.port_or_known_default().unwrap_or(80),
+    };
+
+    let mut body = data.join("&");
+    if get && !body.is_empty() {
+        let query = match url.query() {
+            Some(query) => format!("{query}&{body}"),
+            None => body.clone(),
+        };
+        url.set_query(Some(&query));
+        body.clear();
+    }
+    let method = method.unwrap_or(if body.is_empty() { "GET" } else { "POST" }.to_string());
+
+    let has = |name: &str| headers.iter().any(|(key, _)| key.eq_ignore_ascii_case(name));
+    let path = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    };
+    let mut lines = vec![format!("{method} {path} HTTP/1.1")];
+    if !has("host") {
+        lines.push(format!("Host: {}", target.authority()));
+    }
+    lines.extend(headers.iter().map(|(name, value)| format!("{name}: {value}")));
+    if !body.is_empty() {
+        if !has("content-type") {
+            lines.push("Content-Type: application/x-www-form-urlencoded".to_string());
+        }
+        lines.push(format!("Content-Length: {}", body.len()));
+    }
+
+    Ok(CurlImport { raw: format!("{}\r\n\r\n{body}", lines.join("\r\n")), target })
+}
+
+#[tauri::command]
+pub fn to_curl(raw: String, target: Option<Target>) -> Result<String, String> {
+    generate(&raw, Language::Curl, target)
+}
+
+#[tauri::command]
+pub fn from_curl(cmd: String) -> Result<CurlImport, String> {
+    parse_curl(&cmd)
+}
+
+/// Snippet reproducing a flow or Repeater request in `language`
+#[tauri::command]
+pub fn generate_code(raw: String, language: Language, target: Option<Target>) -> Result<String, String> {
+    generate(&raw, language, target)
+}
+
+/// Saves a raw request to a file, as sent on the wire
+#[tauri::command]
+pub async fn export_raw_request(raw: String, path: String) -> Result<(), String> {
+    tokio::fs::write(&path, raw).await.map_err(|e| format!("Failed to write {path}: {e}"))
+}
+
+/// Loads a raw request file, with bare LF line endings in the head turned into CRLF.
+/// The body is kept byte for byte
+#[tauri::command]
+pub async fn import_raw_request(path: String) -> Result<String, String> {
+    let raw = tokio::fs::read_to_string(&path).await.map_err(|e| format!("Failed to read {path}: {e}"))?;
+    let head_end = [raw.find("\r\n\r\n").map(|i| (i, i + 4)), raw.find("\n\n").map(|i| (i, i + 2))].into_iter()
+        .flatten()
+        .min();
+    let (head, body) = match head_end {
+        Some((end, body_start)) => (&raw[..end], &raw[body_start..]),
+        None => (raw.trim_end_matches(['\r', '\n']), ""),
+    };
+    let head = head.replace("\r\n", "\n").replace("\n", "\r\n");
+    Ok(format!("{head}\r\n\r\n{body}"))
+}
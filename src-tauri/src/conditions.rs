@@ -0,0 +1,200 @@
+use std::{io, sync::Arc, time::Duration};
+
+use hyper::{Response, StatusCode, body::Bytes, header::{CONTENT_LENGTH, CONTENT_TYPE}};
+use log::info;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tokio::{io::{AsyncWrite, AsyncWriteExt}, net::TcpStream, time::sleep};
+use tokio_rustls::server::TlsStream;
+
+use crate::{AppState, network::HostScoped};
+
+/// How often throttled writes are paced
+const THROTTLE_TICK: Duration = Duration::from_millis(100);
+
+fn default_failure_statuses() -> Vec<u16> {
+    vec![503]
+}
+
+/// Simulated network conditions for proxied traffic to matching hosts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConditionRule {
+    pub name: String,
+    pub enabled: bool,
+    /// Host globs the rule applies to, all hosts when empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Added before the request is forwarded
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Random extra latency between 0 and this value
+    #[serde(default)]
+    pub jitter_ms: u64,
+    /// Response bytes per second written back to the client
+    #[serde(default)]
+    pub bandwidth_bps: Option<u64>,
+    /// Chance from 0 to 1 of answering with one of `failure_statuses` instead of forwarding
+    #[serde(default)]
+    pub failure_rate: f64,
+    #[serde(default = "default_failure_statuses")]
+    pub failure_statuses: Vec<u16>,
+    /// Chance from 0 to 1 of dropping the client connection partway through the response
+    #[serde(default)]
+    pub reset_rate: f64,
+}
+
+/// A fault applied to a flow
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Fault {
+    Latency { rule: String, ms: u64 },
+    Throttle { rule: String, bytes_per_sec: u64 },
+    Failure { rule: String, status: u16 },
+    Reset { rule: String, after_bytes: usize },
+}
+
+/// Faults rolled for one request
+#[derive(Debug, Default)]
+pub struct Conditions {
+    pub rule: String,
+    pub delay: Option<Duration>,
+    pub failure: Option<u16>,
+    pub bandwidth: Option<u64>,
+    pub reset: bool,
+    pub faults: Vec<Fault>,
+}
+
+impl HostScoped for ConditionRule {
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+}
+
+impl ConditionRule {
+    fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.failure_rate) || !(0.0..=1.0).contains(&self.reset_rate) {
+            return Err(format!("Rates in condition rule {} must be between 0 and 1", self.name))
+        }
+        if self.bandwidth_bps == Some(0) {
+            return Err(format!("Bandwidth in condition rule {} must be above 0", self.name))
+        }
+        if let Some(status) = self.failure_statuses.iter().find(|status| StatusCode::from_u16(**status).is_err()) {
+            return Err(format!("Invalid failure status {status} in condition rule {}", self.name))
+        }
+        Ok(())
+    }
+
+    /// Decides which faults apply to the next request
+    pub fn roll(&self) -> Conditions {
+        let mut rng = rand::rng();
+        let mut conditions = Conditions { rule: self.name.clone(), ..Default::default() };
+
+        let delay = self.latency_ms + if self.jitter_ms > 0 { rng.random_range(0..=self.jitter_ms) } else { 0 };
+        if delay > 0 {
+            conditions.delay = Some(Duration::from_millis(delay));
+            conditions.faults.push(Fault::Latency { rule: self.name.clone(), ms: delay });
+        }
+
+        if !self.failure_statuses.is_empty() && rng.random_bool(self.failure_rate) {
+            let status = self.failure_statuses[rng.random_range(0..self.failure_statuses.len())];
+            conditions.failure = Some(status);
+            conditions.faults.push(Fault::Failure { rule: self.name.clone(), status });
+            return conditions
+        }
+
+        if let Some(bytes_per_sec) = self.bandwidth_bps {
+            conditions.bandwidth = Some(bytes_per_sec);
+            conditions.faults.push(Fault::Throttle { rule: self.name.clone(), bytes_per_sec });
+        }
+        conditions.reset = rng.random_bool(self.reset_rate);
+
+        conditions
+    }
+}
+
+/// Response sent in place of the server's for an injected failure
+pub fn failure_response(status: u16) -> io::Result<Response<Bytes>> {
+    let body = Bytes::from(format!("Injected failure: {status}\n"));
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain")
+        .header(CONTENT_LENGTH, body.len())
+        .body(body)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid failure response: {e}")))
+}
+
+/// Client connection a reset can be injected on
+pub trait Resettable {
+    /// Socket under any TLS
+    fn socket(&self) -> &TcpStream;
+}
+
+impl Resettable for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl Resettable for TlsStream<TcpStream> {
+    fn socket(&self) -> &TcpStream {
+        self.get_ref().0
+    }
+}
+
+impl Conditions {
+    /// Writes the response to the client, throttled and cut short as rolled.
+    /// Returns an error once the connection has been reset, the caller then drops it so the client sees an RST
+    pub async fn write<S: AsyncWrite + Resettable + Unpin>(&mut self, stream: &mut S, data: &[u8]) -> io::Result<()> {
+        let data = if self.reset && !data.is_empty() {
+            let after_bytes = rand::rng().random_range(0..data.len());
+            self.faults.push(Fault::Reset { rule: self.rule.clone(), after_bytes });
+            &data[..after_bytes]
+        } else {
+            data
+        };
+
+        match self.bandwidth {
+            Some(bytes_per_sec) => {
+                let chunk = ((bytes_per_sec as u128 * THROTTLE_TICK.as_millis() / 1000) as usize).max(1);
+                for part in data.chunks(chunk) {
+                    stream.write_all(part).await?;
+                    stream.flush().await?;
+                    sleep(THROTTLE_TICK).await;
+                }
+            },
+            None => {
+                stream.write_all(data).await?;
+                stream.flush().await?;
+            },
+        }
+
+        if self.reset {
+            // A zero linger makes closing send an RST instead of a FIN, and doesn't block the drop
+            #[allow(deprecated)]
+            stream.socket().set_linger(Some(Duration::ZERO))?;
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, format!("Connection reset by condition rule {}", self.rule)))
+        }
+        Ok(())
+    }
+}
+
+#[tauri::command]
+pub async fn get_condition_rules(state: State<'_, Arc<AppState>>) -> Result<Vec<ConditionRule>, String> {
+    Ok(state.condition_rules.lock().await.clone())
+}
+
+#[tauri::command]
+pub async fn set_condition_rules(state: State<'_, Arc<AppState>>, rules: Vec<ConditionRule>) -> Result<(), String> {
+    for rule in &rules {
+        rule.validate()?;
+    }
+
+    info!("Updated network condition rules: {}", rules.len());
+    *state.condition_rules.lock().await = rules;
+    Ok(())
+}
@@ -0,0 +1,205 @@
+use std::{collections::HashMap, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+use crate::{AppState, client::Target, project, session::apply_headers};
+
+/// A cookie set by a proxied response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// Lowercase, without a leading dot
+    pub domain: String,
+    /// Only sent to `domain` itself when set, otherwise to its subdomains too
+    #[serde(default)]
+    pub host_only: bool,
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default)]
+    pub secure: bool,
+    #[serde(default)]
+    pub http_only: bool,
+    /// Unix time in milliseconds, a session cookie when not set
+    #[serde(default)]
+    pub expires: Option<i64>,
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
+/// Directory of the request path, the path a cookie without a Path attribute is scoped to
+fn default_cookie_path(request_path: &str) -> String {
+    let path = request_path.split(['?', '#']).next().unwrap_or("/");
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string(),
+    }
+}
+
+fn domain_matches(cookie: &Cookie, host: &str) -> bool {
+    let host = host.to_lowercase();
+    host == cookie.domain || (!cookie.host_only && host.ends_with(&format!(".{}", cookie.domain)))
+}
+
+fn path_matches(cookie_path: &str, request_path: &str) -> bool {
+    request_path == cookie_path
+        || (request_path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || request_path[cookie_path.len()..].starts_with('/')))
+}
+
+impl Cookie {
+    fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= now_ms())
+    }
+}
+
+/// Cookies kept with the project and shared by the tools that opt in to them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CookieJar {
+    pub cookies: Vec<Cookie>,
+    /// Changed since the autosave last copied it into the project
+    #[serde(skip)]
+    pub dirty: bool,
+}
+
+impl CookieJar {
+    /// Stores a Set-Cookie header value from a response to `host` for a request to `request_path`.
+    /// An already expired cookie removes the stored one instead, cookies for other domains are ignored
+    pub fn store(&mut self, host: &str, request_path: &str, set_cookie: &str) {
+        let parsed = match cookie::Cookie::parse(set_cookie) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                error!("Ignoring invalid Set-Cookie from {host}: {e}");
+                return
+            },
+        };
+
+        let host = host.to_lowercase();
+        let (domain, host_only) = match parsed.domain() {
+            Some(domain) => (domain.trim_start_matches('.').to_lowercase(), false),
+            None => (host.clone(), true),
+        };
+        let cookie = Cookie {
+            name: parsed.name().to_string(),
+            value: parsed.value().to_string(),
+            domain,
+            host_only,
+            path: parsed.path().filter(|path| path.starts_with('/')).map(str::to_string).unwrap_or(default_cookie_path(request_path)),
+            secure: parsed.secure().unwrap_or(false),
+            http_only: parsed.http_only().unwrap_or(false),
+            expires: match parsed.max_age() {
+                Some(max_age) => Some(now_ms() + max_age.whole_milliseconds() as i64),
+                None => parsed.expires_datetime().map(|expires| (expires.unix_timestamp_nanos() / 1_000_000) as i64),
+            },
+        };
+        if !domain_matches(&cookie, &host) {
+            error!("Ignoring cookie {} from {host} set for domain {}", cookie.name, cookie.domain);
+            return
+        }
+
+        self.cookies.retain(|existing| !(existing.name == cookie.name && existing.domain == cookie.domain && existing.path == cookie.path));
+        if !cookie.is_expired() {
+            self.cookies.push(cookie);
+        }
+        self.dirty = true;
+    }
+
+    /// Cookie header for a request, most specific paths first. `None` when no cookie applies
+    pub fn header(&self, host: &str, request_path: &str, secure: bool) -> Option<String> {
+        let mut cookies = self.cookies.iter()
+            .filter(|cookie| !cookie.is_expired()
+                && (secure || !cookie.secure)
+                && domain_matches(cookie, host)
+                && path_matches(&cookie.path, request_path.split(['?', '#']).next().unwrap_or("/")))
+            .collect::<Vec<&Cookie>>();
+        if cookies.is_empty() {
+            return None
+        }
+
+        cookies.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+        Some(cookies.iter().map(|cookie| format!("{}={}", cookie.name, cookie.value)).collect::<Vec<String>>().join("; "))
+    }
+}
+
+pub fn parse_cookie_header(header: &str) -> Vec<(String, String)> {
+    header.split(";")
+        .filter_map(|pair| pair.split_once("="))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// Cookie header with the cookies of `added` replacing any of the same name in `existing`
+pub fn merge_cookie_header(existing: &str, added: &str) -> String {
+    let added = parse_cookie_header(added);
+    let names = added.iter().map(|(name, _)| name.as_str()).collect::<Vec<&str>>();
+    parse_cookie_header(existing).into_iter()
+        .filter(|(name, _)| !names.contains(&name.as_str()))
+        .chain(added.iter().cloned())
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+/// Cookie header from name and value pairs
+pub fn cookie_header(cookies: &HashMap<String, String>) -> String {
+    cookies.iter().map(|(name, value)| format!("{name}={value}")).collect::<Vec<String>>().join("; ")
+}
+
+/// Jar cookie header for a request from a tool
+pub async fn jar_header(state: &AppState, host: &str, request_path: &str, secure: bool) -> Option<String> {
+    state.cookie_jar.lock().await.header(host, request_path, secure)
+}
+
+/// Raw request with the jar's cookies for the target merged into its Cookie header
+pub async fn inject_jar(state: &AppState, target: &Target, raw: &str) -> String {
+    let path = raw.split_whitespace().nth(1).unwrap_or("/");
+    match jar_header(state, &target.host, path, target.scheme == "https").await {
+        Some(header) => apply_headers(raw, &[("Cookie".to_string(), header)]),
+        None => raw.to_string(),
+    }
+}
+
+/// Stores the Set-Cookie headers of a proxied response, the autosave writes them to the project
+pub async fn store_response_cookies(state: &AppState, host: &str, request_path: &str, set_cookies: Vec<String>) {
+    if set_cookies.is_empty() {
+        return
+    }
+
+    let mut jar = state.cookie_jar.lock().await;
+    for set_cookie in &set_cookies {
+        jar.store(host, request_path, set_cookie);
+    }
+}
+
+#[tauri::command]
+pub async fn get_cookies(state: State<'_, Arc<AppState>>) -> Result<Vec<Cookie>, String> {
+    let mut jar = state.cookie_jar.lock().await;
+    jar.cookies.retain(|cookie| !cookie.is_expired());
+    Ok(jar.cookies.clone())
+}
+
+/// Replaces the jar's cookies, an empty list clears it
+#[tauri::command]
+pub async fn set_cookies(state: State<'_, Arc<AppState>>, cookies: Vec<Cookie>) -> Result<(), String> {
+    if let Some(cookie) = cookies.iter().find(|cookie| cookie.name.is_empty() || cookie.domain.is_empty()) {
+        return Err(format!("Cookie {:?} needs a name and a domain", cookie.name))
+    }
+
+    info!("Updated cookie jar: {}", cookies.len());
+    {
+        let mut jar = state.cookie_jar.lock().await;
+        jar.cookies = cookies.into_iter()
+            .map(|cookie| Cookie { domain: cookie.domain.trim_start_matches('.').to_lowercase(), ..cookie })
+            .collect();
+        jar.dirty = true;
+    }
+    project::flush(&state).await.map_err(|e| e.to_string())
+}
@@ -0,0 +1,157 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MatchKind {
+    /// Any of the statuses
+    Status { codes: Vec<u16> },
+    /// Substring of the body, or a regex when `regex` is set
+    Body {
+        pattern: String,
+        #[serde(default)]
+        regex: bool,
+    },
+    /// Regex matched against the value of every header with the name
+    Header { name: String, pattern: String },
+    /// Regex matched against the Location header, never matches without one
+    Redirect { pattern: String },
+    /// Response size in bytes, both bounds inclusive
+    Length {
+        #[serde(default)]
+        min: Option<usize>,
+        #[serde(default)]
+        max: Option<usize>,
+    },
+    /// Response took at least `delta_ms` longer than the baseline request, never matches without a baseline
+    Timing { delta_ms: i64 },
+}
+
+/// Check on a brute-force response. `negate` turns a check for something present into one for it being absent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Matcher {
+    #[serde(flatten)]
+    pub kind: MatchKind,
+    #[serde(default)]
+    pub negate: bool,
+    /// The kind's pattern compiled by `Criteria::compile`
+    #[serde(skip)]
+    regex: Option<Regex>,
+}
+
+/// What the criteria made of an attempt
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+    /// Neither all success matchers nor any failure matcher matched
+    Unknown,
+}
+
+/// The response of an attempt as the matchers see it
+pub struct Observed<'a> {
+    pub status: u16,
+    /// Raw response, status line and headers then the body
+    pub raw: &'a str,
+    pub time_ms: u128,
+}
+
+impl Observed<'_> {
+    fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        let head = self.raw.split_once("\r\n\r\n").map(|(head, _)| head).unwrap_or(self.raw);
+        head.split("\r\n")
+            .skip(1)
+            .filter_map(|line| line.split_once(":"))
+            .map(|(name, value)| (name.trim(), value.trim()))
+    }
+
+    fn body(&self) -> &str {
+        self.raw.split_once("\r\n\r\n").map(|(_, body)| body).unwrap_or("")
+    }
+}
+
+impl Matcher {
+    fn new(kind: MatchKind) -> Self {
+        Matcher { kind, negate: false, regex: None }
+    }
+
+    /// Checks the matcher and compiles its pattern
+    fn compile(mut self) -> Result<Self, String> {
+        let pattern = match &self.kind {
+            MatchKind::Body { pattern, regex: true } | MatchKind::Header { pattern, .. } | MatchKind::Redirect { pattern } => pattern,
+            MatchKind::Length { min: Some(min), max: Some(max) } if min > max => {
+                return Err(format!("Length matcher has a minimum of {min} above its maximum of {max}"))
+            },
+            _ => return Ok(self),
+        };
+        self.regex = Some(Regex::new(pattern).map_err(|e| format!("Invalid matcher pattern {pattern}: {e}"))?);
+        Ok(self)
+    }
+
+    fn regex_matches(&self, text: &str) -> bool {
+        self.regex.as_ref().is_some_and(|regex| regex.is_match(text))
+    }
+
+    pub fn matches(&self, response: &Observed, baseline_ms: Option<u128>) -> bool {
+        let matched = match &self.kind {
+            MatchKind::Status { codes } => codes.contains(&response.status),
+            MatchKind::Body { regex: true, .. } => self.regex_matches(response.body()),
+            MatchKind::Body { pattern, regex: false } => response.body().contains(pattern.as_str()),
+            MatchKind::Header { name, .. } => response.headers()
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .any(|(_, value)| self.regex_matches(value)),
+            MatchKind::Redirect { .. } => response.headers()
+                .find(|(key, _)| key.eq_ignore_ascii_case("location"))
+                .is_some_and(|(_, location)| self.regex_matches(location)),
+            MatchKind::Length { min, max } => {
+                let length = response.raw.len();
+                min.is_none_or(|min| length >= min) && max.is_none_or(|max| length <= max)
+            },
+            MatchKind::Timing { delta_ms } => match baseline_ms {
+                Some(baseline_ms) => response.time_ms as i128 - baseline_ms as i128 >= *delta_ms as i128,
+                None => return false,
+            },
+        };
+        matched != self.negate
+    }
+}
+
+/// Decides which brute-force attempts worked. An attempt fails when any failure matcher matches
+/// and succeeds when every success matcher does. Without any matchers a 2xx or 3xx status is a success
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Criteria {
+    #[serde(default)]
+    pub success: Vec<Matcher>,
+    #[serde(default)]
+    pub failure: Vec<Matcher>,
+}
+
+impl Criteria {
+    /// Checks the matchers and compiles their patterns, filling in the default success matcher when there are none
+    pub fn compile(self) -> Result<Self, String> {
+        if self.success.is_empty() && self.failure.is_empty() {
+            return Ok(Criteria { success: vec![Matcher::new(MatchKind::Status { codes: (200..400).collect() })], failure: Vec::new() })
+        }
+
+        Ok(Criteria {
+            success: self.success.into_iter().map(Matcher::compile).collect::<Result<Vec<Matcher>, String>>()?,
+            failure: self.failure.into_iter().map(Matcher::compile).collect::<Result<Vec<Matcher>, String>>()?,
+        })
+    }
+
+    /// Whether a matcher compares against the time of a baseline request, which then has to be sent first
+    pub fn needs_baseline(&self) -> bool {
+        self.success.iter().chain(&self.failure).any(|matcher| matches!(matcher.kind, MatchKind::Timing { .. }))
+    }
+
+    pub fn outcome(&self, response: &Observed, baseline_ms: Option<u128>) -> Outcome {
+        if self.failure.iter().any(|matcher| matcher.matches(response, baseline_ms)) {
+            Outcome::Failure
+        } else if !self.success.is_empty() && self.success.iter().all(|matcher| matcher.matches(response, baseline_ms)) {
+            Outcome::Success
+        } else {
+            Outcome::Unknown
+        }
+    }
+}
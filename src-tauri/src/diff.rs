@@ -0,0 +1,229 @@
+use std::sync::Arc;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+use crate::AppState;
+
+/// Past this many comparison cells the middle of a section is reported as replaced wholesale
+const MAX_CELLS: usize = 4_000_000;
+
+/// Responses kept for comparing, older flows can no longer be picked
+pub const MAX_RESPONSES: usize = 10_000;
+
+const HTTP_DATE: &str = r"(?:Mon|Tue|Wed|Thu|Fri|Sat|Sun), \d{2} (?:Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec) \d{4} \d{2}:\d{2}:\d{2} GMT";
+const ISO_DATE: &str = r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:\.\d+)?(?:Z|[+-]\d{2}:?\d{2})?";
+const CSRF_FIELD: &str = r#"(?i)((?:csrf|xsrf|authenticity)[_-]?token["']?\s*[:=]\s*["']?)[^"'&;\s<>]+"#;
+const CSRF_INPUT: &str = r#"(?i)(name=["'](?:_?csrf(?:_?token)?|csrfmiddlewaretoken|authenticity_token|__RequestVerificationToken)["'][^>]*?(?:value|content)=["'])[^"']*"#;
+
+/// Something with a response to compare
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum DiffSource {
+    /// Proxy history flow, Prober or Bruter result
+    Flow { id: String },
+    Repeater { id: String },
+    /// Response text given directly
+    Raw { raw: String },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiffOptions {
+    /// HTTP and ISO 8601 dates
+    #[serde(default)]
+    pub ignore_dates: bool,
+    /// Values of common CSRF token fields, headers and inputs
+    #[serde(default)]
+    pub ignore_csrf: bool,
+    /// Any further regexes whose matches are ignored
+    #[serde(default)]
+    pub ignore_patterns: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffTag {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// A run of text that is in both sides, only in `a` (delete) or only in `b` (insert)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffOp {
+    pub tag: DiffTag,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SectionDiff {
+    pub lines: Vec<DiffOp>,
+    pub words: Vec<DiffOp>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowDiff {
+    /// Status line and headers
+    pub headers: SectionDiff,
+    pub body: SectionDiff,
+    /// Share of words the two responses have in common, from 0 to 1
+    pub similarity: f64,
+}
+
+impl DiffOptions {
+    fn patterns(&self) -> Result<Vec<(Regex, &'static str)>, String> {
+        let mut patterns = Vec::new();
+        let compile = |pattern: &str| Regex::new(pattern).map_err(|e| format!("Invalid pattern {pattern}: {e}"));
+
+        if self.ignore_dates {
+            patterns.push((compile(HTTP_DATE)?, "<date>"));
+            patterns.push((compile(ISO_DATE)?, "<date>"));
+        }
+        if self.ignore_csrf {
+            patterns.push((compile(CSRF_FIELD)?, "${1}<csrf>"));
+            patterns.push((compile(CSRF_INPUT)?, "${1}<csrf>"));
+        }
+        for pattern in &self.ignore_patterns {
+            patterns.push((compile(pattern)?, "<ignored>"));
+        }
+
+        Ok(patterns)
+    }
+}
+
+fn normalize(text: &str, patterns: &[(Regex, &str)]) -> String {
+    patterns.iter().fold(text.to_string(), |text, (regex, replacement)| regex.replace_all(&text, *replacement).to_string())
+}
+
+fn lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+/// Words, whitespace runs and single punctuation characters, so the tokens join back into the text
+fn words(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let class = |c: char| if c.is_alphanumeric() || c == '_' { 0 } else if c.is_whitespace() { 1 } else { 2 };
+
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let kind = class(c);
+        let end = i + c.len_utf8();
+        let continues = kind != 2 && chars.peek().is_some_and(|(_, next)| class(*next) == kind);
+        if !continues {
+            tokens.push(&text[start..end]);
+            start = end;
+        }
+    }
+
+    tokens
+}
+
+fn push(ops: &mut Vec<DiffOp>, tag: DiffTag, text: &str) {
+    match ops.last_mut() {
+        Some(last) if last.tag == tag => last.text.push_str(text),
+        _ => ops.push(DiffOp { tag, text: text.to_string() }),
+    }
+}
+
+/// Longest-common-subsequence diff of two token lists
+fn diff_tokens(a: &[&str], b: &[&str]) -> Vec<DiffOp> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev()).take_while(|(x, y)| x == y).count();
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut ops = Vec::new();
+    for token in &a[..prefix] {
+        push(&mut ops, DiffTag::Equal, token);
+    }
+
+    let (n, m) = (a_mid.len(), b_mid.len());
+    if n * m > MAX_CELLS {
+        a_mid.iter().for_each(|token| push(&mut ops, DiffTag::Delete, token));
+        b_mid.iter().for_each(|token| push(&mut ops, DiffTag::Insert, token));
+    } else {
+        // lcs[i][j] is the LCS length of a_mid[i..] and b_mid[j..]
+        let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i * (m + 1) + j] = if a_mid[i] == b_mid[j] {
+                    lcs[(i + 1) * (m + 1) + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * (m + 1) + j].max(lcs[i * (m + 1) + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < n && j < m {
+            if a_mid[i] == b_mid[j] {
+                push(&mut ops, DiffTag::Equal, a_mid[i]);
+                i += 1;
+                j += 1;
+            } else if lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1] {
+                push(&mut ops, DiffTag::Delete, a_mid[i]);
+                i += 1;
+            } else {
+                push(&mut ops, DiffTag::Insert, b_mid[j]);
+                j += 1;
+            }
+        }
+        a_mid[i..].iter().for_each(|token| push(&mut ops, DiffTag::Delete, token));
+        b_mid[j..].iter().for_each(|token| push(&mut ops, DiffTag::Insert, token));
+    }
+
+    for token in &a[a.len() - suffix..] {
+        push(&mut ops, DiffTag::Equal, token);
+    }
+    ops
+}
+
+fn diff_section(a: &str, b: &str) -> SectionDiff {
+    SectionDiff {
+        lines: diff_tokens(&lines(a), &lines(b)),
+        words: diff_tokens(&words(a), &words(b)),
+    }
+}
+
+/// Compares two raw responses after normalisation
+pub fn diff_responses(a: &str, b: &str, options: &DiffOptions) -> Result<FlowDiff, String> {
+    let patterns = options.patterns()?;
+    let (a, b) = (normalize(a, &patterns), normalize(b, &patterns));
+    let (a_head, a_body) = a.split_once("\r\n\r\n").unwrap_or((&a, ""));
+    let (b_head, b_body) = b.split_once("\r\n\r\n").unwrap_or((&b, ""));
+
+    let headers = diff_section(a_head, b_head);
+    let body = diff_section(a_body, b_body);
+
+    let count = |ops: &[DiffOp], tag: DiffTag| ops.iter().filter(|op| op.tag == tag).map(|op| words(&op.text).len()).sum::<usize>();
+    let ops = headers.words.iter().chain(body.words.iter()).cloned().collect::<Vec<DiffOp>>();
+    let (equal, deleted, inserted) = (count(&ops, DiffTag::Equal), count(&ops, DiffTag::Delete), count(&ops, DiffTag::Insert));
+    let total = 2 * equal + deleted + inserted;
+    let similarity = if total == 0 { 1.0 } else { (2 * equal) as f64 / total as f64 };
+
+    Ok(FlowDiff { headers, body, similarity })
+}
+
+async fn resolve(state: &AppState, source: DiffSource) -> Result<String, String> {
+    match source {
+        DiffSource::Flow { id } => state.responses.lock().await.get(&id).cloned()
+            .ok_or(format!("No response recorded for {id}")),
+        DiffSource::Repeater { id } => {
+            let project = state.project.lock().await;
+            let entry = project.data.repeater.values()
+                .flat_map(|tab| tab.entries.iter())
+                .find(|entry| entry.id == id)
+                .ok_or(format!("No Repeater entry {id}"))?;
+            entry.response.as_ref().map(|res| res.raw.clone()).ok_or(format!("Repeater entry {id} has no response"))
+        },
+        DiffSource::Raw { raw } => Ok(raw),
+    }
+}
+
+#[tauri::command]
+pub async fn diff_flows(state: State<'_, Arc<AppState>>, a: DiffSource, b: DiffSource, options: Option<DiffOptions>) -> Result<FlowDiff, String> {
+    let a = resolve(&state, a).await?;
+    let b = resolve(&state, b).await?;
+    diff_responses(&a, &b, &options.unwrap_or_default())
+}
@@ -0,0 +1,132 @@
+use std::{collections::HashMap, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use log::info;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use uuid::Uuid;
+
+use crate::{AppState, project};
+
+/// Named sets of variables for `{{env.name}}` placeholders, kept with the project
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Environments {
+    /// Environment used when a send doesn't name one
+    #[serde(default)]
+    pub active: Option<String>,
+    #[serde(default)]
+    pub sets: HashMap<String, HashMap<String, String>>,
+}
+
+impl Environments {
+    /// Variables of the named environment, or of the active one
+    pub fn variables(&self, name: Option<&str>) -> Result<HashMap<String, String>, String> {
+        match name.or(self.active.as_deref()) {
+            Some(name) => self.sets.get(name).cloned().ok_or(format!("No environment named {name}")),
+            None => Ok(HashMap::new()),
+        }
+    }
+}
+
+fn unix_time() -> std::time::Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// Value of a single placeholder. `None` for text that isn't a known placeholder, which is left as written
+fn evaluate(expr: &str, variables: &HashMap<String, String>) -> Option<Result<String, String>> {
+    let expr = expr.trim();
+    if let Some(name) = expr.strip_prefix("env.") {
+        return Some(variables.get(name.trim()).cloned().ok_or(format!("Unknown environment variable {name}")))
+    }
+
+    match expr {
+        "uuid" => return Some(Ok(Uuid::new_v4().to_string())),
+        "timestamp" => return Some(Ok(unix_time().as_secs().to_string())),
+        "timestamp_ms" => return Some(Ok(unix_time().as_millis().to_string())),
+        _ => {},
+    }
+
+    let (function, args) = expr.strip_suffix(")")?.split_once("(")?;
+    match function.trim() {
+        "random_int" => {
+            let bounds = args.split_once(",").and_then(|(min, max)| Some((min.trim().parse::<i64>().ok()?, max.trim().parse::<i64>().ok()?)));
+            Some(match bounds {
+                Some((min, max)) if min <= max => Ok(rand::rng().random_range(min..=max).to_string()),
+                _ => Err(format!("Invalid random_int bounds: {args}")),
+            })
+        },
+        "base64" => Some(Ok(BASE64_STANDARD.encode(args))),
+        _ => None,
+    }
+}
+
+/// Expands the `{{...}}` placeholders in `text`. Placeholders nest, inner ones are expanded first,
+/// so `{{base64({{env.user}}:{{env.pass}})}}` works. Expanded values are never expanded again
+pub fn expand(text: &str, variables: &HashMap<String, String>) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let inner = &rest[start + 2..];
+
+        // Find the matching close, skipping over nested placeholders
+        let mut depth = 1;
+        let mut i = 0;
+        let end = loop {
+            if i >= inner.len() {
+                break None
+            }
+            if inner[i..].starts_with("{{") {
+                depth += 1;
+                i += 2;
+            } else if inner[i..].starts_with("}}") {
+                depth -= 1;
+                if depth == 0 {
+                    break Some(i)
+                }
+                i += 2;
+            } else {
+                i += inner[i..].chars().next().map_or(1, char::len_utf8);
+            }
+        };
+
+        let Some(end) = end else {
+            out.push_str(&rest[start..]);
+            return Ok(out)
+        };
+
+        let expr = expand(&inner[..end], variables)?;
+        match evaluate(&expr, variables) {
+            Some(value) => out.push_str(&value?),
+            None => out.push_str(&format!("{{{{{expr}}}}}")),
+        }
+        rest = &inner[end + 2..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[tauri::command]
+pub async fn get_environments(state: State<'_, Arc<AppState>>) -> Result<Environments, String> {
+    Ok(state.project.lock().await.data.environments.clone())
+}
+
+#[tauri::command]
+pub async fn set_environments(state: State<'_, Arc<AppState>>, environments: Environments) -> Result<(), String> {
+    if let Some(active) = &environments.active {
+        if !environments.sets.contains_key(active) {
+            return Err(format!("No environment named {active}"))
+        }
+    }
+
+    info!("Updated environments: {}", environments.sets.len());
+    {
+        let mut project = state.project.lock().await;
+        project.data.environments = environments;
+        project.mark_dirty();
+    }
+    project::flush(&state).await.map_err(|e| e.to_string())
+}
@@ -0,0 +1,225 @@
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tls_parser::{SNIType, TlsClientHelloContents, TlsExtension, TlsExtensionType, TlsMessage, TlsMessageHandshake, parse_tls_extensions, parse_tls_plaintext};
+use tokio_rustls::rustls::{ClientConnection, pki_types::CertificateDer};
+use x509_parser::{extensions::GeneralName, prelude::{FromDer, X509Certificate}};
+
+const EXT_SNI: u16 = 0x0000;
+const EXT_ALPN: u16 = 0x0010;
+
+/// Flows whose TLS details are kept, older flows can no longer be inspected
+pub const MAX_FLOW_TLS: usize = 10_000;
+
+/// Length of a complete TLS record starting at the beginning of the buffer, if the header is there
+pub fn record_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 5 {
+        return None
+    }
+    Some(5 + u16::from_be_bytes([buf[3], buf[4]]) as usize)
+}
+
+/// GREASE values (RFC 8701) are random per client and left out of fingerprints
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn join(values: &[u16], sep: &str, hex: bool) -> String {
+    values.iter()
+        .map(|v| if hex { format!("{:04x}", v) } else { v.to_string() })
+        .collect::<Vec<String>>()
+        .join(sep)
+}
+
+/// First 12 hex characters of the SHA256, as used by JA4
+fn truncated_sha256(input: &str) -> String {
+    if input.is_empty() {
+        return "000000000000".to_string()
+    }
+    format!("{:x}", Sha256::digest(input.as_bytes()))[..12].to_string()
+}
+
+/// What the client offered in its ClientHello
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientHelloSummary {
+    pub sni: Option<String>,
+    /// Legacy version field of the hello
+    pub version: u16,
+    pub supported_versions: Vec<u16>,
+    pub ciphers: Vec<u16>,
+    pub extensions: Vec<u16>,
+    pub alpn: Vec<String>,
+    pub groups: Vec<u16>,
+    pub point_formats: Vec<u8>,
+    pub signature_algorithms: Vec<u16>,
+    pub ja3: String,
+    pub ja3_hash: String,
+    pub ja4: String,
+}
+
+impl ClientHelloSummary {
+    fn from_hello(hello: &TlsClientHelloContents) -> Self {
+        let mut summary = ClientHelloSummary {
+            version: hello.version.0,
+            ciphers: hello.ciphers.iter().map(|cipher| cipher.0).collect(),
+            ..Default::default()
+        };
+
+        let extensions = hello.ext.and_then(|ext| parse_tls_extensions(ext).ok()).map(|(_, exts)| exts).unwrap_or_default();
+        for extension in &extensions {
+            summary.extensions.push(TlsExtensionType::from(extension).0);
+            match extension {
+                TlsExtension::SNI(names) => {
+                    summary.sni = names.iter()
+                        .find(|(kind, _)| *kind == SNIType::HostName)
+                        .map(|(_, name)| String::from_utf8_lossy(name).to_string());
+                },
+                TlsExtension::ALPN(protocols) => {
+                    summary.alpn = protocols.iter().map(|p| String::from_utf8_lossy(p).to_string()).collect();
+                },
+                TlsExtension::EllipticCurves(groups) => summary.groups = groups.iter().map(|group| group.0).collect(),
+                TlsExtension::EcPointFormats(formats) => summary.point_formats = formats.to_vec(),
+                TlsExtension::SignatureAlgorithms(algorithms) => summary.signature_algorithms = algorithms.clone(),
+                TlsExtension::SupportedVersions(versions) => summary.supported_versions = versions.iter().map(|v| v.0).collect(),
+                _ => {}
+            }
+        }
+
+        summary.ja3 = summary.ja3_string();
+        summary.ja3_hash = format!("{:x}", Md5::digest(summary.ja3.as_bytes()));
+        summary.ja4 = summary.ja4_string();
+        summary
+    }
+
+    fn ja3_string(&self) -> String {
+        let strip = |values: &[u16]| values.iter().copied().filter(|v| !is_grease(*v)).collect::<Vec<u16>>();
+        let formats = self.point_formats.iter().map(|f| f.to_string()).collect::<Vec<String>>().join("-");
+
+        format!("{},{},{},{},{}",
+            self.version,
+            join(&strip(&self.ciphers), "-", false),
+            join(&strip(&self.extensions), "-", false),
+            join(&strip(&self.groups), "-", false),
+            formats)
+    }
+
+    fn ja4_string(&self) -> String {
+        let version = self.supported_versions.iter()
+            .copied()
+            .filter(|v| !is_grease(*v))
+            .max()
+            .unwrap_or(self.version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            _ => "00",
+        };
+
+        let mut ciphers = self.ciphers.iter().copied().filter(|v| !is_grease(*v)).collect::<Vec<u16>>();
+        let mut extensions = self.extensions.iter().copied().filter(|v| !is_grease(*v)).collect::<Vec<u16>>();
+        let alpn = match self.alpn.first().map(|p| p.chars().collect::<Vec<char>>()) {
+            Some(chars) if !chars.is_empty() => format!("{}{}", chars[0], chars[chars.len() - 1]),
+            _ => "00".to_string(),
+        };
+
+        let prefix = format!("t{}{}{:02}{:02}{}",
+            version,
+            if self.sni.is_some() { "d" } else { "i" },
+            ciphers.len().min(99),
+            extensions.len().min(99),
+            alpn);
+
+        ciphers.sort();
+        extensions.retain(|ext| *ext != EXT_SNI && *ext != EXT_ALPN);
+        extensions.sort();
+
+        let mut extension_input = join(&extensions, ",", true);
+        if !self.signature_algorithms.is_empty() {
+            extension_input.push('_');
+            extension_input.push_str(&join(&self.signature_algorithms, ",", true));
+        }
+
+        format!("{}_{}_{}", prefix, truncated_sha256(&join(&ciphers, ",", true)), truncated_sha256(&extension_input))
+    }
+}
+
+/// Summary of the ClientHello in the first TLS record
+pub fn parse_client_hello(buf: &[u8]) -> Option<ClientHelloSummary> {
+    let (_, record) = parse_tls_plaintext(buf).ok()?;
+    record.msg.iter().find_map(|msg| match msg {
+        TlsMessage::Handshake(TlsMessageHandshake::ClientHello(hello)) => Some(ClientHelloSummary::from_hello(hello)),
+        _ => None,
+    })
+}
+
+/// Server name from the SNI extension of a ClientHello record
+pub fn parse_sni(buf: &[u8]) -> Option<String> {
+    parse_client_hello(buf)?.sni
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CertificateSummary {
+    pub subject: String,
+    pub issuer: String,
+    pub sans: Vec<String>,
+    pub serial: String,
+    pub not_before: String,
+    pub not_after: String,
+    pub expired: bool,
+}
+
+impl CertificateSummary {
+    fn from_der(der: &CertificateDer) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der.as_ref()).ok()?;
+        let sans = cert.subject_alternative_name().ok().flatten()
+            .map(|ext| ext.value.general_names.iter().map(|name| match name {
+                GeneralName::DNSName(dns) => dns.to_string(),
+                GeneralName::IPAddress(ip) => format!("{:?}", ip),
+                other => other.to_string(),
+            }).collect())
+            .unwrap_or_default();
+
+        Some(CertificateSummary {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            sans,
+            serial: cert.raw_serial_as_string(),
+            not_before: cert.validity().not_before.to_string(),
+            not_after: cert.validity().not_after.to_string(),
+            expired: !cert.validity().is_valid(),
+        })
+    }
+}
+
+/// Both sides of the TLS a flow travelled over
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowTls {
+    pub client: Option<ClientHelloSummary>,
+    pub upstream: Option<UpstreamTls>,
+}
+
+/// What was negotiated with the upstream server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamTls {
+    pub version: Option<String>,
+    pub cipher: Option<String>,
+    pub alpn: Option<String>,
+    /// Leaf first
+    pub certificates: Vec<CertificateSummary>,
+}
+
+impl UpstreamTls {
+    pub fn from_connection(conn: &ClientConnection) -> Self {
+        UpstreamTls {
+            version: conn.protocol_version().map(|v| format!("{:?}", v)),
+            cipher: conn.negotiated_cipher_suite().map(|suite| format!("{:?}", suite.suite())),
+            alpn: conn.alpn_protocol().map(|p| String::from_utf8_lossy(p).to_string()),
+            certificates: conn.peer_certificates()
+                .map(|certs| certs.iter().filter_map(CertificateSummary::from_der).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
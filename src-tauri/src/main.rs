@@ -9,12 +9,33 @@ use snare_script::Script;
 use tokio::sync::Mutex;
 use std::sync::atomic::AtomicBool;
 use std::{collections::HashMap, sync::Arc};
-use tauri::Manager;
+use tauri::{Manager, RunEvent};
 use log::error;
 
+mod bruter;
+mod client;
+mod codegen;
+mod conditions;
+mod cookies;
+mod criteria;
+mod diff;
+mod environments;
+mod handshake;
+mod mapping;
 mod network;
+mod passthrough;
+mod project;
 mod proxy;
+mod race;
+mod recent;
+mod repeater;
+mod rewrite;
 mod script;
+mod session;
+mod signing;
+mod socks;
+mod tls;
+mod upstream;
 
 #[derive(Clone, Serialize, Deserialize)]
 struct AppRequest {
@@ -34,25 +55,68 @@ struct AppResponse {
     raw: String,
 }
 
-#[derive(Serialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
 struct Res {
+    /// Request id the response answers
+    id: String,
     url: String,
     status: String,
     headers: Vec<(String, String)>,
     body: String,
     raw: String,
+    /// Body length in bytes
+    size: usize,
+    timings: client::Timings,
+    tls: Option<handshake::UpstreamTls>,
+    /// Redirects followed before this response
+    hops: Vec<repeater::Hop>,
 }
 
 struct AppState {
     intercept: AtomicBool,
-    scripts: Arc<Mutex<HashMap<String, (Script, String, bool)>>>
+    scripts: Arc<Mutex<HashMap<String, (Script, String, bool)>>>,
+    upstream: Mutex<upstream::UpstreamConfig>,
+    listener: Mutex<proxy::ListenerConfig>,
+    passthrough: Mutex<passthrough::PassthroughConfig>,
+    tls_profiles: Mutex<Vec<tls::TlsProfile>>,
+    /// TLS details of the latest proxy flows by id
+    flow_tls: Mutex<recent::Recent<handshake::FlowTls>>,
+    replace_rules: Mutex<Vec<rewrite::ReplaceRule>>,
+    map_rules: Mutex<Vec<mapping::MapRule>>,
+    condition_rules: Mutex<Vec<conditions::ConditionRule>>,
+    session_rules: Mutex<Vec<session::SessionRule>>,
+    /// Sessions of the session rules by rule name
+    sessions: Mutex<HashMap<String, session::Session>>,
+    /// Held while a session rule's macro runs, by rule name, so each rule logs in once at a time
+    session_refreshes: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    signing_profiles: Mutex<Vec<signing::SigningProfile>>,
+    project: Mutex<project::ProjectStore>,
+    /// Kept apart from the project so proxied responses don't contend for it, copied in by the autosave
+    cookie_jar: Mutex<cookies::CookieJar>,
+    /// Raw responses of the latest proxy flows and tool results by id, for comparing
+    responses: Mutex<recent::Recent<String>>,
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let state = Arc::new(AppState {
         intercept: AtomicBool::new(false),
-        scripts: Arc::new(Mutex::new(HashMap::new()))
+        scripts: Arc::new(Mutex::new(HashMap::new())),
+        upstream: Mutex::new(upstream::UpstreamConfig::default()),
+        listener: Mutex::new(proxy::ListenerConfig::default()),
+        passthrough: Mutex::new(passthrough::PassthroughConfig::default()),
+        tls_profiles: Mutex::new(Vec::new()),
+        flow_tls: Mutex::new(recent::Recent::new(handshake::MAX_FLOW_TLS)),
+        replace_rules: Mutex::new(Vec::new()),
+        map_rules: Mutex::new(Vec::new()),
+        condition_rules: Mutex::new(Vec::new()),
+        session_rules: Mutex::new(Vec::new()),
+        sessions: Mutex::new(HashMap::new()),
+        session_refreshes: Mutex::new(HashMap::new()),
+        signing_profiles: Mutex::new(Vec::new()),
+        project: Mutex::new(project::ProjectStore::default()),
+        cookie_jar: Mutex::new(cookies::CookieJar::default()),
+        responses: Mutex::new(recent::Recent::new(diff::MAX_RESPONSES)),
     });
 
     let state_clone = state.clone();
@@ -60,6 +124,12 @@ pub fn run() {
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_dialog::init())
         .setup(|app| {
+            let path = app.path().app_data_dir()?.join("project.json");
+            let project = project::ProjectStore::open(path);
+            *state_clone.cookie_jar.blocking_lock() = project.data.cookies.clone();
+            *state_clone.project.blocking_lock() = project;
+            tauri::async_runtime::spawn(project::autosave(state_clone.clone()));
+
             let app_handle = app.handle().clone();
             let task = tauri::async_runtime::spawn(async move {
                 proxy::start_proxy(app_handle, state_clone).await.unwrap();
@@ -72,7 +142,16 @@ pub fn run() {
         .manage(state)
         .invoke_handler(tauri::generate_handler![
             network::toggle_intercept,
-            network::send_request,
+            repeater::send_request,
+            repeater::send_raw_request,
+            repeater::list_repeater_history,
+            repeater::get_repeater_entry,
+            repeater::repeater_back,
+            repeater::repeater_forward,
+            diff::diff_flows,
+            environments::get_environments,
+            environments::set_environments,
+            race::send_race,
             parse_jwt_token,
             encode_jwt,
             network::probe_dirs,
@@ -81,10 +160,48 @@ pub fn run() {
             script::get_args,
             script::update_script,
             script::remove_script,
-            script::add_script
+            script::add_script,
+            upstream::get_upstream_config,
+            upstream::set_upstream_config,
+            proxy::get_listener_config,
+            proxy::set_listener_config,
+            passthrough::get_passthrough_config,
+            passthrough::set_passthrough_config,
+            passthrough::add_passthrough_host,
+            tls::get_tls_profiles,
+            tls::set_tls_profiles,
+            proxy::get_flow_tls,
+            rewrite::get_replace_rules,
+            rewrite::set_replace_rules,
+            mapping::get_map_rules,
+            mapping::set_map_rules,
+            conditions::get_condition_rules,
+            conditions::set_condition_rules,
+            session::get_session_rules,
+            session::set_session_rules,
+            session::get_sessions,
+            session::run_session_macro,
+            cookies::get_cookies,
+            cookies::set_cookies,
+            codegen::to_curl,
+            codegen::from_curl,
+            codegen::generate_code,
+            codegen::export_raw_request,
+            codegen::import_raw_request,
+            signing::get_signing_profiles,
+            signing::set_signing_profiles,
+            bruter::bruteforce_template
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            if let RunEvent::Exit = event {
+                let state = app.state::<Arc<AppState>>().inner().clone();
+                if let Err(e) = tauri::async_runtime::block_on(project::flush(&state)) {
+                    error!("Failed to save project on exit: {e}");
+                }
+            }
+        });
 }
 
 
@@ -261,7 +378,7 @@ fn encode_jwt(header: String, payload: String, secret: String) -> JwtEncodeResul
     let payload_encoded = URL_SAFE_NO_PAD.encode(payload);
 
     if let Some(alg) = header_json.get("alg") {
-        if let Ok(algorithm) = alg.as_str().unwrap().parse::<Algorithm>() {
+        if let Some(Ok(algorithm)) = alg.as_str().map(|alg| alg.parse::<Algorithm>()) {
             let message = format!("{}.{}", header_encoded, payload_encoded);
             let key = EncodingKey::from_secret(secret.as_bytes());
             result.signature = match sign(&message.as_bytes(), &key, algorithm) {
@@ -0,0 +1,173 @@
+use std::{io, path::Path, sync::Arc};
+
+use hyper::{Response, StatusCode, body::Bytes, header::{CONTENT_LENGTH, CONTENT_TYPE}};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+use crate::{AppState, client::Target, network::{glob_matches, host_matches}};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MapAction {
+    /// Answer from a file on disk without contacting the server
+    Local {
+        file: String,
+        /// Guessed from the file extension when not set
+        #[serde(default)]
+        content_type: Option<String>,
+    },
+    /// Send the request elsewhere, unset parts keep their original value
+    Remote {
+        #[serde(default)]
+        scheme: Option<String>,
+        #[serde(default)]
+        host: Option<String>,
+        #[serde(default)]
+        port: Option<u16>,
+        /// Replaces the path, the original query string is kept
+        #[serde(default)]
+        path: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapRule {
+    pub name: String,
+    pub enabled: bool,
+    /// Methods the rule applies to, empty matches any method
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Host glob, e.g. `api.prod.example.com`
+    pub host: String,
+    /// Path glob including the query string, e.g. `/static/*.js*`
+    pub path: String,
+    pub action: MapAction,
+}
+
+fn guess_content_type(file: &str) -> &'static str {
+    let extension = Path::new(file).extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "application/javascript",
+        "css" => "text/css",
+        "json" | "map" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "wasm" => "application/wasm",
+        "txt" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Recorded on the flow next to the original target
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mapping {
+    pub rule: String,
+    /// File served or URL the request was sent to
+    pub destination: String,
+}
+
+pub enum Mapped {
+    Local(Response<Bytes>),
+    Remote { raw: String, target: Target },
+}
+
+pub fn select_rule<'a>(rules: &'a [MapRule], method: &str, host: &str, path: &str) -> Option<&'a MapRule> {
+    rules.iter().find(|rule| rule.matches(method, host, path))
+}
+
+impl MapRule {
+    fn matches(&self, method: &str, host: &str, path: &str) -> bool {
+        self.enabled
+            && (self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+            && host_matches(&self.host, host)
+            && glob_matches(&self.path, path)
+    }
+
+    /// Applies the rule to a raw request going to `target`
+    pub async fn apply(&self, raw: &str, target: &Target) -> io::Result<(Mapped, Mapping)> {
+        match &self.action {
+            MapAction::Local { file, content_type } => {
+                let body = tokio::fs::read(file).await.map_err(|e| {
+                    io::Error::new(e.kind(), format!("Map Local rule {} failed reading {file}: {e}", self.name))
+                })?;
+                let content_type = content_type.clone().unwrap_or(guess_content_type(file).to_string());
+
+                let res = Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, content_type)
+                    .header(CONTENT_LENGTH, body.len())
+                    .body(Bytes::from(body))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid Map Local response: {e}")))?;
+
+                info!("Map Local {} served {file}", self.name);
+                Ok((Mapped::Local(res), Mapping { rule: self.name.clone(), destination: file.clone() }))
+            },
+            MapAction::Remote { scheme, host, port, path } => {
+                let scheme = scheme.clone().unwrap_or(target.scheme.clone());
+                let default_port = if scheme == "https" { 443 } else { 80 };
+                let new_target = Target {
+                    port: port.unwrap_or(if scheme == target.scheme { target.port } else { default_port }),
+                    host: host.clone().unwrap_or(target.host.clone()),
+                    scheme,
+                };
+
+                let (head, body) = raw.split_once("\r\n\r\n").unwrap_or((raw, ""));
+                let mut lines = head.split("\r\n");
+                let request_line = lines.next().unwrap_or("");
+                let (method, old_path, version) = match request_line.split_whitespace().collect::<Vec<&str>>()[..] {
+                    [m, p, v] => (m, p, v),
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed request line")),
+                };
+
+                // Absolute-form targets from plain proxy clients are reduced to their path
+                let old_path = match old_path.split_once("://") {
+                    Some((_, rest)) => rest.find("/").map(|i| &rest[i..]).unwrap_or("/"),
+                    None => old_path,
+                };
+                let new_path = match path {
+                    Some(path) => match old_path.split_once("?") {
+                        Some((_, query)) => format!("{path}?{query}"),
+                        None => path.clone(),
+                    },
+                    None => old_path.to_string(),
+                };
+
+                let authority = new_target.authority();
+                let headers = lines.map(|line| match line.split_once(":") {
+                    Some((key, _)) if key.trim().eq_ignore_ascii_case("host") => format!("{key}: {authority}"),
+                    _ => line.to_string(),
+                }).collect::<Vec<String>>();
+
+                let head = std::iter::once(format!("{method} {new_path} {version}")).chain(headers).collect::<Vec<String>>().join("\r\n");
+                let destination = new_target.url(&new_path);
+
+                info!("Map Remote {} sent request to {destination}", self.name);
+                Ok((Mapped::Remote { raw: format!("{head}\r\n\r\n{body}"), target: new_target }, Mapping { rule: self.name.clone(), destination }))
+            },
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn get_map_rules(state: State<'_, Arc<AppState>>) -> Result<Vec<MapRule>, String> {
+    Ok(state.map_rules.lock().await.clone())
+}
+
+#[tauri::command]
+pub async fn set_map_rules(state: State<'_, Arc<AppState>>, rules: Vec<MapRule>) -> Result<(), String> {
+    for rule in &rules {
+        if let MapAction::Remote { scheme: Some(scheme), .. } = &rule.action {
+            if scheme != "http" && scheme != "https" {
+                return Err(format!("Map Remote rule {} has unsupported scheme {scheme}", rule.name))
+            }
+        }
+    }
+
+    info!("Updated map rules: {}", rules.len());
+    *state.map_rules.lock().await = rules;
+    Ok(())
+}
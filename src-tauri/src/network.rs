@@ -1,17 +1,17 @@
-use std::{fs::read_to_string, io::{self, BufRead}, path::{Path, PathBuf}, sync::{Arc, atomic::Ordering}, time::Duration};
+use std::{fs::read_to_string, io::{self, BufRead}, path::{Path, PathBuf}, sync::{Arc, atomic::Ordering}, time::{Duration, Instant}};
 use base64::{Engine, prelude::BASE64_STANDARD};
 use futures::{StreamExt, stream::FuturesUnordered};
-use hyper::{HeaderMap, Method, StatusCode};
+use hyper::{Method, StatusCode};
 use rcgen::{Certificate, CertificateParams, DnType, Issuer, KeyPair};
-use reqwest::Client;
+use reqwest::{Client, ClientBuilder, Url, header::{COOKIE, HeaderName, HeaderValue}, redirect::Policy};
 use serde::{Deserialize, Serialize};
-use tauri::{AppHandle, Emitter, State, http::{HeaderName, HeaderValue}};
+use tauri::{AppHandle, Emitter, Manager, State};
 use tokio::{fs::File, io::{AsyncBufReadExt, AsyncReadExt, BufReader}, net::TcpStream, sync::Semaphore};
 use tokio_rustls::rustls::{ServerConfig, pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer}};
-use serde_json::json;
 use log::{info, error};
+use uuid::Uuid;
 
-use crate::{AppState, Res};
+use crate::{AppState, cookies::{self, merge_cookie_header}, criteria::{Criteria, Observed, Outcome}, session, signing::{self, SignInput}, tls};
 
 pub async fn read_request(stream: &mut TcpStream) -> io::Result<String> {
     let mut buffer = [0u8; 4096];
@@ -106,109 +106,185 @@ pub async fn create_server_config(cert_der: Vec<u8>, key_der: Vec<u8>) -> io::Re
     Ok(config)
 }
 
-#[tauri::command]
-pub async fn send_request(app: AppHandle, raw: String) {
-    let client = Client::new();
-    let raw_owned = raw.clone();
-
-    if let Some((headers_str, body)) = raw_owned.split_once("\r\n\r\n") {
-        let mut headers = HeaderMap::new();
-
-        let headers_split: Vec<String> = headers_str
-            .lines()
-            .skip(1)
-            .map(|line| line.to_string())
-            .collect();
-
-        let request_line = headers_str.lines().next().unwrap();
-        let (method, path, _version) =
-            match request_line.split_whitespace().collect::<Vec<&str>>()[..] {
-                [m, p, v] => (m, p, v),
-                _ => {
-                    error!("Invalid request line: {}", request_line);
-                    return;
-                }
-            };
+/// Case-insensitive host glob, see [`glob_matches`]. A port on the host is ignored.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = match host.rsplit_once(":") {
+        Some((name, port)) if !name.ends_with("]") && port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    glob_matches(&pattern.to_lowercase(), &host.to_lowercase())
+}
 
-        let mut host = None;
-        for header in headers_split {
-            if let Some((key, value)) = header.split_once(":") {
-                if key.to_lowercase() == "host" {
-                    host = Some(value.trim().to_string());
-                }
+/// Rule that can be switched off and is scoped to host globs, applying to every host when it has none
+pub trait HostScoped {
+    fn enabled(&self) -> bool;
+    fn hosts(&self) -> &[String];
 
-                let hname = HeaderName::from_bytes(key.trim().as_bytes()).unwrap();
-                let hvalue = HeaderValue::from_str(value.trim()).unwrap();
-                headers.insert(hname, hvalue);
-            }
+    fn applies_to(&self, host: &str) -> bool {
+        self.enabled() && (self.hosts().is_empty() || self.hosts().iter().any(|pattern| host_matches(pattern, host)))
+    }
+}
+
+/// First rule that applies to `host`
+pub fn select_scoped<'a, R: HostScoped>(rules: &'a [R], host: &str) -> Option<&'a R> {
+    rules.iter().find(|rule| rule.applies_to(host))
+}
+
+/// Glob where `*` matches any run of characters and `?` a single one
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let text = text.chars().collect::<Vec<char>>();
+
+    let (mut p, mut h) = (0, 0);
+    let mut backtrack = None;
+    while h < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[h]) {
+            p += 1;
+            h += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, h));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            h = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
         }
+    }
 
-        headers.remove("accept-encoding");
+    pattern[p..].iter().all(|c| *c == '*')
+}
 
-        let url = match host {
-            Some(host) => {
-                format!("https://{host}{path}")
-            }
-            None => path.to_string(),
-        };
+/// Client shared by the proxy and the tools, routed through the configured upstream proxies
+/// and using the TLS profile for the URL's host. The URL is rewritten when the profile overrides SNI
+pub async fn create_client(state: &AppState, url: &mut Url) -> io::Result<Client> {
+    build_client(client_builder(state, url).await?)
+}
 
-        info!("Sending to {:?} {}", method, url);
+async fn client_builder(state: &AppState, url: &mut Url) -> io::Result<ClientBuilder> {
+    let builder = state.upstream.lock().await.apply(Client::builder());
+    let profiles = state.tls_profiles.lock().await.clone();
+    tls::apply_profile(&profiles, builder, url).await
+}
 
-        let mut req = match method {
-            "GET" => client.get(url.clone()),
-            "POST" => client.post(url.clone()),
-            _ => return,
-        };
+fn build_client(builder: ClientBuilder) -> io::Result<Client> {
+    builder.build().map_err(|e| {
+        io::Error::new(io::ErrorKind::Other, format!("Failed to build client: {e}"))
+    })
+}
 
-        req = req.headers(headers);
+#[tauri::command]
+pub fn toggle_intercept(state: State<'_, Arc<AppState>>, intercept_toggle: bool) {
+    state.intercept.store(intercept_toggle, Ordering::Relaxed);
+    info!("Intercept toggled: {}", intercept_toggle);
+}
 
-        if method == "POST" {
-            req = req.body(body.to_string());
-        }
+/// Raw HTTP/1.1 form of a tool response, kept for comparing
+async fn response_raw(res: reqwest::Response) -> String {
+    let status_line = format!("HTTP/1.1 {}", res.status());
+    let headers = res.headers()
+        .iter()
+        .map(|(k, v)| format!("{}: {}\r\n", k, v.to_str().unwrap_or("")))
+        .collect::<String>();
+    let body = res.text().await.unwrap_or_default();
+    format!("{status_line}\r\n{headers}\r\n{body}")
+}
 
-        let res = req.send().await;
+/// Stores a tool response for `diff_flows` and returns its id
+pub async fn store_response(state: &AppState, raw: String) -> String {
+    let id = Uuid::new_v4().to_string();
+    state.responses.lock().await.insert(id.clone(), raw);
+    id
+}
 
-        if let Ok(res) = res {
-            let mut response = Res::default();
-            response.url = res.url().to_string();
-            response.status = res.status().to_string();
-            response.headers = res
-                .headers()
-                .iter()
-                .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
-                .collect();
+/// Adds the jar's cookies when asked to and the host's session, then signs the request with the host's signing profile
+async fn prepare_request(state: &AppState, host: &str, mut request: reqwest::Request, use_cookie_jar: bool) -> reqwest::Request {
+    let mut headers = Vec::new();
+    if use_cookie_jar {
+        let secure = request.url().scheme() == "https";
+        if let Some(cookies) = cookies::jar_header(state, host, request.url().path(), secure).await {
+            headers.push(("Cookie".to_string(), cookies));
+        }
+    }
+    headers.extend(session::headers(state, host).await);
 
-            let body_bytes = res.bytes().await.unwrap();
-            let body_string = String::from_utf8_lossy(&body_bytes).to_string();
-            response.body = body_string.clone();
-            response.raw = [format!("HTTP/1.1 {}", response.status), response.headers.iter().map(|(k, v)| format!("{}: {}", k, v)).collect::<Vec<String>>().join("\r\n"), "".to_string(), body_string].join("\r\n");
+    for (name, value) in headers {
+        let value = match (name.eq_ignore_ascii_case("cookie"), request.headers().get(COOKIE).and_then(|v| v.to_str().ok())) {
+            (true, Some(existing)) => merge_cookie_header(existing, &value),
+            _ => value,
+        };
+        set_header(&mut request, &name, &value);
+    }
 
-            let _ = app.emit("forwarded-response-received", json!(response));
+    if let Some(signer) = signing::signer(state, host).await {
+        let url = request.url().clone();
+        let authority = match url.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_string(),
+        };
+        let input = SignInput {
+            method: request.method().as_str(),
+            host: &authority,
+            path: url.path(),
+            query: url.query().unwrap_or(""),
+            headers: request.headers().iter()
+                .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).to_string()))
+                .collect(),
+            body: request.body().and_then(|body| body.as_bytes()).unwrap_or_default(),
+        };
+        match signer.sign(&input) {
+            Ok(headers) => headers.iter().for_each(|(name, value)| set_header(&mut request, name, value)),
+            Err(e) => error!("Failed to sign request to {host}: {e}"),
         }
     }
+    request
 }
 
-#[tauri::command]
-pub fn toggle_intercept(state: State<'_, Arc<AppState>>, intercept_toggle: bool) {
-    state.intercept.store(intercept_toggle, Ordering::Relaxed);
-    info!("Intercept toggled: {}", intercept_toggle);
+fn set_header(request: &mut reqwest::Request, name: &str, value: &str) {
+    if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+        request.headers_mut().insert(name, value);
+    }
 }
 
-pub async fn send_req(client: Arc<Client>, url: &String, user: Arc<String>, pass: String, method: Method, attack_type: AttackType) -> anyhow::Result<Option<(String, String)>> {
-    let mut request;
-    match method {
-        Method::GET => {
-            request = client.get(url);
-        }, 
-        Method::POST => {
-            request = client.post(url);
+/// Sends a tool request with the host's session injected, retrying once after a re-login
+/// when the response shows the session expired. Returns the status and raw response.
+/// `host` is the target as the user gave it, the request URL holds the SNI name when a TLS profile overrides it
+async fn execute(state: &AppState, client: &Client, host: &str, request: reqwest::RequestBuilder, use_cookie_jar: bool) -> reqwest::Result<(StatusCode, String)> {
+    let request = request.build()?;
+    let retry = request.try_clone();
+    let sent = Instant::now();
+
+    let res = client.execute(prepare_request(state, host, request, use_cookie_jar).await).await?;
+    let status = res.status();
+    let raw = response_raw(res).await;
+    match retry {
+        Some(retry) if session::refresh_if_invalid(state, host, status.as_u16(), &raw, sent).await => {
+            let res = client.execute(prepare_request(state, host, retry, use_cookie_jar).await).await?;
+            Ok((res.status(), response_raw(res).await))
         },
-        _ => {
-            return Err(anyhow::anyhow!("Invalid method given"))
-        }
+        _ => Ok((status, raw)),
+    }
+}
+
+/// What every attempt of one login brute force shares
+struct Login {
+    client: Client,
+    /// Host the brute force targets, before any SNI override of `url`
+    host: String,
+    url: String,
+    attack_type: AttackType,
+    use_cookie_jar: bool,
+}
+
+/// Sends one login attempt and returns the status, the raw response and how long it took
+async fn send_req(state: &AppState, login: &Login, user: &str, pass: &str) -> anyhow::Result<(StatusCode, String, u128)> {
+    let mut request = match method_for(login.attack_type) {
+        Method::POST => login.client.post(&login.url),
+        _ => login.client.get(&login.url),
     };
 
-    match attack_type {
+    match login.attack_type {
         AttackType::Form => {
             let body = format!("username={}&password={}", user, pass);
             request = request
@@ -221,56 +297,93 @@ pub async fn send_req(client: Arc<Client>, url: &String, user: Arc<String>, pass
         }
     }
 
-    let res = request.send().await?;
-    let status = res.status();
-    
-    if status.is_success() || status.is_redirection() {
-        return Ok(Some((user.to_string(), pass)))
-    }
-
-    Ok(None)
+    let started = Instant::now();
+    let (status, raw) = execute(state, &login.client, &login.host, request, login.use_cookie_jar).await?;
+    Ok((status, raw, started.elapsed().as_millis()))
 }
 
-async fn send_reqs(
-    client: Arc<Client>,
+/// Longest a guided login attempt, or its baseline, may take
+const BRUTEFORCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Outcome of one guided login attempt
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginAttempt {
+    user: String,
+    pass: String,
     url: String,
-    users: Vec<String>,
-    passwords: Vec<String>,
-    attack_type: AttackType
-) -> Vec<(String, String)> {
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<(String, String)>(4096);
-    let url = Arc::new(url);
-    let method = Arc::new(match attack_type {
+    status: Option<u16>,
+    time_ms: u128,
+    /// Id of the stored response
+    id: Option<String>,
+    error: Option<String>,
+    /// `Unknown` when the request failed
+    outcome: Outcome,
+}
+
+fn method_for(attack_type: AttackType) -> Method {
+    match attack_type {
         AttackType::Basic => Method::GET,
         AttackType::Form => Method::POST,
-    });
+    }
+}
+
+/// Time of a login with the first user and a password that can't be right
+async fn baseline(state: &AppState, login: &Login, user: &str) -> Option<u128> {
+    match send_req(state, login, user, &Uuid::new_v4().to_string()).await {
+        Ok((_, _, time_ms)) => {
+            info!("Baseline login took {time_ms}ms");
+            Some(time_ms)
+        },
+        Err(e) => {
+            error!("Baseline login failed, timing matchers will not match: {e}");
+            None
+        },
+    }
+}
+
+async fn send_reqs(state: Arc<AppState>, login: Arc<Login>, users: Vec<String>, passwords: Vec<String>, criteria: Arc<Criteria>) -> Vec<LoginAttempt> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<LoginAttempt>(4096);
+
+    let baseline_ms = match (criteria.needs_baseline(), users.first()) {
+        (true, Some(user)) => baseline(&state, &login, user).await,
+        _ => None,
+    };
 
     let mut futures = FuturesUnordered::new();
 
     for user in users {
         let user = Arc::new(user);
         for pass in passwords.iter() {
-            let client = client.clone();
+            let login = login.clone();
             let user = user.clone();
-            let url = url.clone();
-            let method = method.clone();
             let tx = tx.clone();
             let pass = pass.clone();
+            let state = state.clone();
+            let criteria = criteria.clone();
 
             futures.push(async move {
-                let res = 
-                send_req(
-                    client, 
-                    &url, 
-                    user, 
-                    pass, 
-                    (*method).clone(), 
-                    attack_type
-                ).await;
-
-                if let Ok(Some(found)) = res {
-                    let _ = tx.send(found).await;
+                let res = send_req(&state, &login, &user, &pass).await;
+
+                let mut attempt = LoginAttempt {
+                    user: user.to_string(),
+                    pass,
+                    url: login.url.clone(),
+                    status: None,
+                    time_ms: 0,
+                    id: None,
+                    error: None,
+                    outcome: Outcome::Unknown,
+                };
+                match res {
+                    Ok((status, raw, time_ms)) => {
+                        attempt.status = Some(status.as_u16());
+                        attempt.time_ms = time_ms;
+                        attempt.outcome = criteria.outcome(&Observed { status: status.as_u16(), raw: &raw, time_ms }, baseline_ms);
+                        attempt.id = Some(store_response(&state, raw).await);
+                    },
+                    Err(e) => attempt.error = Some(e.to_string()),
                 }
+                let _ = tx.send(attempt).await;
             });
         }
     }
@@ -289,12 +402,14 @@ async fn send_reqs(
 
 #[derive(Serialize, Deserialize, Clone)]
 struct Dir {
+    /// Id of the stored response
+    id: String,
     url: String,
     status: String,
 }
 
 #[tauri::command]
-pub async fn probe_dirs(host: String, wordlist: String, rate_limit: usize, state: AppHandle) {
+pub async fn probe_dirs(host: String, wordlist: String, rate_limit: usize, use_cookie_jar: Option<bool>, state: AppHandle) {
     let accepted_codes = Arc::new(vec![
         StatusCode::OK,
         StatusCode::CREATED,
@@ -310,10 +425,19 @@ pub async fn probe_dirs(host: String, wordlist: String, rate_limit: usize, state
     let mut interval = tokio::time::interval(Duration::from_millis(
         (1000.0 / rate_limit as f64) as u64
     ));
-    let client = Client::new();
+    let app_state = state.state::<Arc<AppState>>().inner().clone();
+    let mut base_url = match Url::parse(&host) {
+        Ok(url) => url,
+        Err(e) => { error!("Invalid host {host}: {e}"); return; }
+    };
+    let target_host = Arc::new(base_url.host_str().unwrap_or("").to_string());
+    let client = match create_client(&app_state, &mut base_url).await {
+        Ok(client) => client,
+        Err(e) => { error!("{e}"); return; }
+    };
     let semaphore = Arc::new(Semaphore::new(200));
     let mut handles = vec![];
-    let mut host = host;
+    let mut host = base_url.to_string();
 
     if host.ends_with("/") {
         host.pop();
@@ -340,21 +464,26 @@ pub async fn probe_dirs(host: String, wordlist: String, rate_limit: usize, state
         let state = state.clone();
         let client = client.clone();
         let host = host.clone();
+        let target_host = target_host.clone();
         let dir = dir.to_string();
         let accepted_clone = accepted_codes.clone();
         let semaphore = semaphore.clone();
+        let app_state = app_state.clone();
 
         let handle = tokio::spawn(async move {
             let _permit = semaphore.acquire_owned().await.unwrap();
             let url = format!("{}/{}", host, dir);
 
-            if let Ok(resp) = client.get(&url).send().await {
-                if accepted_clone.contains(&resp.status()) {
+            if let Ok((status, raw)) = execute(&app_state, &client, &target_host, client.get(&url), use_cookie_jar.unwrap_or(false)).await {
+                if accepted_clone.contains(&status) {
+                    let status = status.as_str().to_string();
+                    let id = store_response(&app_state, raw).await;
                     let _ = state.emit(
                         "dir-received",
                         Dir {
+                            id,
                             url,
-                            status: resp.status().as_str().to_string(),
+                            status,
                         },
                     );
                 }
@@ -375,8 +504,10 @@ pub enum AttackType {
     Basic
 }
 
+/// Tries every user with every password and emits all attempts as `bruteforce-responses`, each with
+/// its outcome under `criteria`. Redirects aren't followed so matchers see the login's own response
 #[tauri::command]
-pub async fn bruteforce(file_paths: Vec<String>, attack_type: String, url: String, app_handle: AppHandle) {
+pub async fn bruteforce(file_paths: Vec<String>, attack_type: String, url: String, criteria: Option<Criteria>, use_cookie_jar: Option<bool>, app_handle: AppHandle) {
     let attack_type = match attack_type.as_str() {
         "form" => AttackType::Form,
         "basic" => AttackType::Basic,
@@ -391,7 +522,20 @@ pub async fn bruteforce(file_paths: Vec<String>, attack_type: String, url: Strin
         Ok(reader.lines().filter_map(Result::ok).collect())
     };
 
-    let client = Client::new();
+    let state = app_handle.state::<Arc<AppState>>().inner().clone();
+    let mut target_url = match Url::parse(&url) {
+        Ok(url) => url,
+        Err(e) => { error!("Invalid url {url}: {e}"); return; }
+    };
+    let host = target_url.host_str().unwrap_or("").to_string();
+    let criteria = match criteria.unwrap_or_default().compile() {
+        Ok(criteria) => criteria,
+        Err(e) => { error!("{e}"); return; }
+    };
+    let client = match client_builder(&state, &mut target_url).await.and_then(|builder| build_client(builder.redirect(Policy::none()).timeout(BRUTEFORCE_TIMEOUT))) {
+        Ok(client) => client,
+        Err(e) => { error!("{e}"); return; }
+    };
 
     let users_path = PathBuf::from(Path::new(&file_paths[0]));
     let users = match read_lines(&users_path) {
@@ -411,7 +555,8 @@ pub async fn bruteforce(file_paths: Vec<String>, attack_type: String, url: Strin
         }
     };
 
-    let responses: Vec<(String, String, String)> = send_reqs(Arc::new(client), url.clone(), users, passwords, attack_type).await.iter().map(|val| (val.0.clone(), val.1.clone(), url.clone())).collect();
+    let login = Login { client, host, url: target_url.to_string(), attack_type, use_cookie_jar: use_cookie_jar.unwrap_or(false) };
+    let responses = send_reqs(state, Arc::new(login), users, passwords, Arc::new(criteria)).await;
     
     let _ = app_handle.emit("bruteforce-responses", responses);
 }
\ No newline at end of file
@@ -0,0 +1,112 @@
+use std::{collections::HashMap, sync::Arc};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+use crate::{AppState, network::host_matches};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PassthroughRule {
+    /// Host glob, e.g. `*.apple.com`
+    pub host: String,
+    /// Ports the rule applies to, empty matches any port
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    pub enabled: bool,
+}
+
+impl PassthroughRule {
+    fn matches(&self, host: &str, port: u16) -> bool {
+        self.enabled
+            && (self.ports.is_empty() || self.ports.contains(&port))
+            && host_matches(&self.host, host)
+    }
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+/// Hosts whose TLS is tunnelled untouched instead of being intercepted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PassthroughConfig {
+    pub rules: Vec<PassthroughRule>,
+    /// Add a passthrough rule once a host has rejected our certificate `failure_threshold` times in a row
+    #[serde(default)]
+    pub auto_passthrough: bool,
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Consecutive handshake failures per host
+    #[serde(skip)]
+    failures: HashMap<String, u32>,
+}
+
+impl Default for PassthroughConfig {
+    fn default() -> Self {
+        PassthroughConfig {
+            rules: Vec::new(),
+            auto_passthrough: false,
+            failure_threshold: default_failure_threshold(),
+            failures: HashMap::new(),
+        }
+    }
+}
+
+impl PassthroughConfig {
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        self.rules.iter().any(|rule| rule.matches(host, port))
+    }
+
+    pub fn add_host(&mut self, host: &str, port: u16) {
+        if self.matches(host, port) {
+            return;
+        }
+
+        info!("Adding passthrough rule for {host}:{port}");
+        self.rules.push(PassthroughRule {
+            host: host.to_string(),
+            ports: vec![port],
+            enabled: true,
+        });
+    }
+
+    /// Counts a failed handshake, returns the failure streak and whether passthrough was applied
+    pub fn record_failure(&mut self, host: &str, port: u16) -> (u32, bool) {
+        let failures = self.failures.entry(host.to_lowercase()).or_insert(0);
+        *failures += 1;
+        let failures = *failures;
+
+        let applied = self.auto_passthrough && failures >= self.failure_threshold;
+        if applied {
+            self.add_host(host, port);
+        }
+
+        (failures, applied)
+    }
+
+    pub fn record_success(&mut self, host: &str) {
+        self.failures.remove(&host.to_lowercase());
+    }
+}
+
+#[tauri::command]
+pub async fn get_passthrough_config(state: State<'_, Arc<AppState>>) -> Result<PassthroughConfig, String> {
+    Ok(state.passthrough.lock().await.clone())
+}
+
+#[tauri::command]
+pub async fn set_passthrough_config(state: State<'_, Arc<AppState>>, config: PassthroughConfig) -> Result<(), String> {
+    info!("Updated passthrough config: {} rules", config.rules.len());
+    let mut passthrough = state.passthrough.lock().await;
+    let failures = std::mem::take(&mut passthrough.failures);
+    *passthrough = PassthroughConfig { failures, ..config };
+    Ok(())
+}
+
+/// Accepts the passthrough offered by a `tls-handshake-failed` event
+#[tauri::command]
+pub async fn add_passthrough_host(state: State<'_, Arc<AppState>>, host: String, port: u16) -> Result<(), String> {
+    state.passthrough.lock().await.add_host(&host, port);
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use std::{collections::HashMap, io, path::PathBuf, sync::Arc, time::Duration};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{AppState, cookies::CookieJar, environments::Environments, repeater::RepeaterTab};
+
+/// How often changes made in the background are written, so a burst of sends costs one write
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Held from serializing to the end of the write, so an older snapshot can't land after a newer one
+static WRITE_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Everything kept with the project between sessions
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Project {
+    /// Repeater history keyed by tab
+    #[serde(default)]
+    pub repeater: HashMap<String, RepeaterTab>,
+    #[serde(default)]
+    pub environments: Environments,
+    #[serde(default)]
+    pub cookies: CookieJar,
+}
+
+/// The project, written back to its JSON file by `flush` on explicit edits and by the autosave otherwise
+#[derive(Debug, Default)]
+pub struct ProjectStore {
+    path: Option<PathBuf>,
+    pub data: Project,
+    /// Changed since it was last written
+    dirty: bool,
+}
+
+impl ProjectStore {
+    /// Loads the project at `path`, starting an empty one when the file doesn't exist yet.
+    /// A file that doesn't parse is moved aside to `.bak` rather than overwritten by the first save
+    pub fn open(path: PathBuf) -> Self {
+        let data = match std::fs::read_to_string(&path).map(|contents| serde_json::from_str::<Project>(&contents)) {
+            Ok(Ok(data)) => data,
+            Ok(Err(e)) => {
+                let backup = path.with_extension("json.bak");
+                error!("Failed to parse project {}, moving it to {}: {e}", path.display(), backup.display());
+                if let Err(e) = std::fs::rename(&path, &backup) {
+                    error!("Failed to move project {} aside, not saving over it: {e}", path.display());
+                    return ProjectStore::default()
+                }
+                Project::default()
+            },
+            Err(_) => Project::default(),
+        };
+
+        info!("Opened project {}", path.display());
+        ProjectStore { path: Some(path), data, dirty: false }
+    }
+
+    /// Leaves the change for the autosave to write
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Path and contents to write, `None` when there's nowhere to write them
+    fn serialize(&mut self) -> io::Result<Option<(PathBuf, String)>> {
+        let Some(path) = self.path.clone() else {
+            return Ok(None)
+        };
+        let contents = serde_json::to_string(&self.data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to serialize project: {e}")))?;
+        self.dirty = false;
+        Ok(Some((path, contents)))
+    }
+}
+
+/// Writes next to the project and renames over it, so a crash mid-write leaves the old file intact
+async fn write(path: PathBuf, contents: String) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let temp = path.with_extension("json.tmp");
+    tokio::fs::write(&temp, contents).await?;
+    tokio::fs::rename(temp, path).await
+}
+
+/// Writes the project when it or the cookie jar has unsaved changes. Only serializing holds the project lock
+pub async fn flush(state: &AppState) -> io::Result<()> {
+    let _write = WRITE_LOCK.lock().await;
+    let cookies = {
+        let mut jar = state.cookie_jar.lock().await;
+        let changed = jar.dirty.then(|| jar.clone());
+        jar.dirty = false;
+        changed
+    };
+    if let Some(cookies) = cookies {
+        let mut project = state.project.lock().await;
+        project.data.cookies = cookies;
+        project.mark_dirty();
+    }
+
+    let serialized = {
+        let mut project = state.project.lock().await;
+        if !project.dirty {
+            return Ok(())
+        }
+        project.serialize()?
+    };
+
+    match serialized {
+        Some((path, contents)) => write(path, contents).await,
+        None => Ok(()),
+    }
+}
+
+/// Flushes unsaved changes every `AUTOSAVE_INTERVAL` for as long as the app runs
+pub async fn autosave(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(AUTOSAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = flush(&state).await {
+            error!("Failed to save project: {e}");
+        }
+    }
+}
@@ -1,18 +1,54 @@
-use std::{collections::HashMap, error::Error, io, ops::Deref, process::exit, sync::{Arc, atomic::Ordering}, time::Duration};
+use std::{error::Error, io, ops::Deref, process::exit, sync::{Arc, atomic::Ordering}, time::{Duration, Instant}};
 
-use hyper::HeaderMap;
+use hyper::{Response, body::Bytes, header::SET_COOKIE};
 use log::{error, info};
 use rcgen::{Issuer, KeyPair};
-use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
-use snare_script::Script;
-use tauri::{AppHandle, Emitter, State, http::{HeaderName, HeaderValue}};
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::Mutex, time::sleep};
-use tokio_rustls::{TlsAcceptor, server::TlsStream};
+use tauri::{AppHandle, Emitter, State};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, copy_bidirectional}, net::{TcpListener, TcpStream}, time::{sleep, timeout}};
+use tokio_rustls::{TlsAcceptor, rustls, server::TlsStream};
 use uuid::Uuid;
 
-use crate::{AppState, network::{create_server_config, generate_cert, get_domain, load_ca, read_request}};
+use crate::{AppState, client::{self, Target}, conditions::{Fault, Resettable, failure_response}, cookies, handshake::{self, ClientHelloSummary, FlowTls, UpstreamTls}, mapping::{Mapped, Mapping, select_rule as select_map_rule}, network::{create_server_config, generate_cert, get_domain, load_ca, read_request, select_scoped}, rewrite::{RuleTarget, apply_rules}, session, signing, socks};
+
+/// How long to wait for a tunnelled client to speak before assuming a server-first protocol
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerMode {
+    #[default]
+    Http,
+    Socks5,
+    /// Clients redirected to the listener without knowing about it, routed by SNI and Host
+    Invisible,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListenerConfig {
+    pub mode: ListenerMode,
+    /// `host:port` used in invisible mode when neither SNI nor a Host header names the target
+    #[serde(default)]
+    pub invisible_fallback: Option<String>,
+}
+
+#[tauri::command]
+pub async fn get_flow_tls(state: State<'_, Arc<AppState>>, id: String) -> Result<FlowTls, String> {
+    state.flow_tls.lock().await.get(&id).cloned().ok_or(format!("No TLS details recorded for flow {id}"))
+}
+
+#[tauri::command]
+pub async fn get_listener_config(state: State<'_, Arc<AppState>>) -> Result<ListenerConfig, String> {
+    Ok(state.listener.lock().await.clone())
+}
+
+#[tauri::command]
+pub async fn set_listener_config(state: State<'_, Arc<AppState>>, config: ListenerConfig) -> Result<(), String> {
+    info!("Listener config set to {:?}", config);
+    *state.listener.lock().await = config;
+    Ok(())
+}
 
 fn parse_request(raw: String, id: String) -> io::Result<FlowRequest> {
     let mut lines = raw.split("\r\n");
@@ -46,7 +82,8 @@ fn parse_request(raw: String, id: String) -> io::Result<FlowRequest> {
     Ok(req)
 }
 
-async fn parse_response(res: Response, id: String) -> io::Result<FlowResponse> {
+/// Raw HTTP/1.1 form of an upstream response
+fn response_raw(res: &Response<Bytes>) -> String {
     let status = res.status().to_string();
     let headers = res.headers()
     .iter()
@@ -57,15 +94,13 @@ async fn parse_response(res: Response, id: String) -> io::Result<FlowResponse> {
         )
     })
     .collect::<Vec<(String, String)>>();
-    let body = res.text().await.map_err(|e| {
-        io::Error::new(io::ErrorKind::InvalidData, 
-            format!("Failed parsing body: {e}"))
-    })?;
+    let body = String::from_utf8_lossy(res.body()).to_string();
 
     let status_line = format!("HTTP/1.1 {status}\r\n");
 
+    // The body is already de-chunked, so it is re-framed with a content-length
     let mut headers_raw = headers.iter().map(|(k, v)| {
-        if !v.starts_with("transfer-encoding") {
+        if k != "transfer-encoding" {
             let v = v.to_string();
             format!("{k}: {v}\r\n")
         } else {
@@ -73,12 +108,48 @@ async fn parse_response(res: Response, id: String) -> io::Result<FlowResponse> {
         }
     }).collect::<Vec<String>>();
     if !headers.iter().any(|(k, _)| k.to_lowercase() == "content-length") {
-        headers_raw.push(format!("content-length: {}", body.bytes().len().to_string()));
+        headers_raw.push(format!("content-length: {}\r\n", body.bytes().len().to_string()));
     }
 
-    let raw = vec![status_line, headers_raw.join(""), "\r\n".to_string(), body.clone()].join("");
+    vec![status_line, headers_raw.join(""), "\r\n".to_string(), body].join("")
+}
 
-    Ok(FlowResponse::new(id.to_string(), status.to_string(), headers, body.to_string(), raw))
+fn parse_response(raw: String, id: String) -> io::Result<FlowResponse> {
+    let Some((head, body)) = raw.split_once("\r\n\r\n") else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, 
+            format!("Malformed response")))
+    };
+
+    let mut lines = head.split("\r\n");
+    let status = lines.next()
+        .and_then(|line| line.split_once(" "))
+        .map(|(_, status)| status.to_string())
+        .unwrap_or_default();
+    let headers = lines
+        .filter_map(|line| line.split_once(":"))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect::<Vec<(String, String)>>();
+
+    Ok(FlowResponse::new(id.to_string(), status, headers, body.to_string(), raw.clone()))
+}
+
+/// Host header of a raw request
+fn request_host(raw: &str) -> Option<String> {
+    raw.split("\r\n\r\n").next()?
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(":"))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// Where a request on this connection goes, from its Host header or the connection's fallback
+fn request_target(raw: &str, info: &ConnectionInfo) -> io::Result<Target> {
+    let Some(authority) = request_host(raw).or(info.fallback.clone()) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "No Host header to forward the request to"))
+    };
+    let (host, port) = split_authority(&authority, if info.scheme == "https" { 443 } else { 80 });
+    Ok(Target { scheme: info.scheme.to_string(), host, port })
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -89,12 +160,16 @@ pub struct FlowRequest {
     host: String,
     headers: String,
     body: String,
-    raw: String
+    raw: String,
+    /// Match-and-replace rules that fired
+    rules: Vec<String>,
+    /// Map Local or Map Remote rule that changed where the request went
+    mapping: Option<Mapping>,
 }
 
 impl FlowRequest {
     fn new(id: String, method: String, path: String, host: String, headers: String, body: String, raw: String) -> Self {
-        FlowRequest { id, method, path, host, headers, body, raw }
+        FlowRequest { id, method, path, host, headers, body, raw, rules: Vec::new(), mapping: None }
     }
 }
 
@@ -105,22 +180,66 @@ pub struct FlowResponse {
     headers: Vec<(String, String)>,
     body: String,
     raw: String,
+    /// Match-and-replace rules that fired
+    rules: Vec<String>,
+    /// Simulated network conditions applied
+    faults: Vec<Fault>,
 }
 
 impl FlowResponse {
     fn new(id: String, status: String, headers: Vec<(String, String)>, body: String, raw: String) -> Self {
-        FlowResponse { id, status, headers, body, raw }
+        FlowResponse { id, status, headers, body, raw, rules: Vec::new(), faults: Vec::new() }
     }
 }
 
+/// Metadata for a tunnel that was relayed without being decrypted
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PassthroughEntry {
+    id: String,
+    host: String,
+    port: u16,
+    bytes_sent: u64,
+    bytes_received: u64,
+    duration_ms: u128,
+    error: Option<String>,
+}
+
+/// A client refusing the handshake with our certificate, usually because of pinning
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeFailure {
+    host: String,
+    port: u16,
+    reason: String,
+    /// Consecutive failures for the host
+    failures: u32,
+    passthrough_applied: bool,
+}
+
 #[derive(Debug)]
 enum Flow {
     Request(FlowRequest),
     Response(FlowResponse),
+    Passthrough(PassthroughEntry),
+    HandshakeFailure(HandshakeFailure),
+}
+
+/// What we know about the client connection requests arrive on
+#[derive(Debug, Clone)]
+struct ConnectionInfo {
+    scheme: &'static str,
+    /// Authority used when a request has no Host header
+    fallback: Option<String>,
+    client_hello: Option<ClientHelloSummary>,
 }
 
-async fn handle_server_connection(tx: Arc<tokio::sync::mpsc::Sender<Flow>>, tls_stream: &mut TlsStream<TcpStream>, req_raw: String, scripts: &Arc<Mutex<HashMap<String, (Script, String, bool)>>>) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    let scripts = scripts.lock().await;
+impl ConnectionInfo {
+    fn new(scheme: &'static str) -> Self {
+        ConnectionInfo { scheme, fallback: None, client_hello: None }
+    }
+}
+
+async fn handle_server_connection<S: AsyncWrite + Resettable + Unpin>(tx: Arc<tokio::sync::mpsc::Sender<Flow>>, client_stream: &mut S, req_raw: String, state: &Arc<AppState>, info: &ConnectionInfo) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let scripts = state.scripts.lock().await;
     let mut req = req_raw.clone();
     info!("Scripts: {:?}", scripts.keys());
 
@@ -136,25 +255,74 @@ async fn handle_server_connection(tx: Arc<tokio::sync::mpsc::Sender<Flow>>, tls_
         })?;
         info!("Script result: {}", req);
     }
+    drop(scripts);
+
+    let mut target = request_target(&req, info)?;
+    let host = target.host.clone();
+    let rules = state.replace_rules.lock().await.clone();
+    let (mut req, request_rules) = apply_rules(&rules, RuleTarget::Request, &host, &req);
+
     // Receive from client
     let id = Uuid::new_v4().to_string();
-    let _ = tx.send(Flow::Request(parse_request(req.clone(), id.clone())?)).await;
+    let mut flow_req = parse_request(req.clone(), id.clone())?;
+    flow_req.rules = request_rules;
+
+    // History keeps the original request, the mapping records where it actually went
+    let map_rule = select_map_rule(&state.map_rules.lock().await, &flow_req.method, &host, &flow_req.path).cloned();
+    let mut local = None;
+    if let Some(rule) = map_rule {
+        let (mapped, mapping) = rule.apply(&req, &target).await?;
+        flow_req.mapping = Some(mapping);
+        match mapped {
+            Mapped::Local(res) => local = Some(res),
+            Mapped::Remote { raw, target: new_target } => {
+                req = raw;
+                target = new_target;
+            },
+        }
+    }
+
+    let path = flow_req.path.clone();
+    let _ = tx.send(Flow::Request(flow_req)).await;
     info!("Flow sent to receiver");
 
     // Send to and receive from server
     info!("Forwarding to client");
-    let res = forward_to_server(req).await?;
+    let mut conditions = select_scoped(&state.condition_rules.lock().await, &host)
+        .map(|rule| rule.roll())
+        .unwrap_or_default();
+    if let Some(delay) = conditions.delay {
+        sleep(delay).await;
+    }
+
+    let (res, upstream_tls) = match (local, conditions.failure) {
+        (_, Some(status)) => (failure_response(status)?, None),
+        (Some(res), None) => (res, None),
+        (None, None) => forward_to_server(state, req, &target).await?,
+    };
+    let set_cookies = res.headers().get_all(SET_COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(str::to_string)
+        .collect();
+    cookies::store_response_cookies(state, &target.host, &path, set_cookies).await;
+    if info.client_hello.is_some() || upstream_tls.is_some() {
+        let flow_tls = FlowTls { client: info.client_hello.clone(), upstream: upstream_tls };
+        state.flow_tls.lock().await.insert(id.clone(), flow_tls);
+    }
+
     info!("Parsing response");
-    let flow_res = Flow::Response(parse_response(res, id).await?);
+    let (res_raw, response_rules) = apply_rules(&rules, RuleTarget::Response, &host, &response_raw(&res));
+    let mut flow_res = parse_response(res_raw, id)?;
+    flow_res.rules = response_rules;
+
     // Send response back to client
-    if let Flow::Response(res) = &flow_res {
-        let _ = tls_stream.write_all(res.raw.as_bytes()).await;
-        let _ = tls_stream.flush().await;
-    }
+    let written = conditions.write(client_stream, flow_res.raw.as_bytes()).await;
+    flow_res.faults = conditions.faults;
+    state.responses.lock().await.insert(flow_res.id.clone(), flow_res.raw.clone());
 
-    let _ = tx.send(flow_res).await;
+    let _ = tx.send(Flow::Response(flow_res)).await;
     info!("Sent response flow");
-    Ok(())
+    Ok(written?)
 }
 
 pub async fn start_proxy(app_handle: AppHandle, state: Arc<AppState>) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
@@ -168,11 +336,9 @@ pub async fn start_proxy(app_handle: AppHandle, state: Arc<AppState>) -> Result<
     let (tx, mut rx) = tokio::sync::mpsc::channel::<Flow>(100);
     let tx = Arc::new(tx);
     let state_clone = state.clone();
-    let scripts = state.scripts.clone();
 
     tokio::spawn(async move {
         loop {
-            let scripts_clone = scripts.clone();
             if !state_clone.intercept.load(Ordering::Relaxed) {
                 continue;
             }
@@ -180,17 +346,14 @@ pub async fn start_proxy(app_handle: AppHandle, state: Arc<AppState>) -> Result<
             if let Ok((stream, _)) = listener.accept().await {
                 let issuer = issuer.clone();
                 let tx = tx.clone();
+                let state = state_clone.clone();
                 tokio::spawn(async move {
-                    let mut tls_stream = handle_client_connection(stream, issuer).await?;
-                    loop {
-                        let req_raw = match read_http_request(&mut tls_stream).await {
-                            Ok(r) => r,
-                            Err(_) => break
-                        };
-                        let _ = handle_server_connection(tx.clone(), &mut tls_stream, req_raw, &scripts_clone).await?;
+                    let config = state.listener.lock().await.clone();
+                    match config.mode {
+                        ListenerMode::Http => handle_client_connection(stream, issuer, tx, state).await,
+                        ListenerMode::Socks5 => handle_socks_connection(stream, issuer, tx, state).await,
+                        ListenerMode::Invisible => handle_invisible_connection(stream, issuer, tx, state, config.invisible_fallback).await,
                     }
-
-                    Ok::<(), Box<dyn Error + Send + Sync + 'static>>(())
                 });
             }
             sleep(Duration::from_millis(100)).await;
@@ -202,6 +365,10 @@ pub async fn start_proxy(app_handle: AppHandle, state: Arc<AppState>) -> Result<
             let _ = app_handle.emit("request-received", json!(req)).inspect_err(|e| error!("Flow receiver error (request): {e}"));
         } else if let Flow::Response(res) = &flow {
             let _ = app_handle.emit("response-received", json!(res)).inspect_err(|e| error!("Flow receiver error (response): {e}"));
+        } else if let Flow::Passthrough(entry) = &flow {
+            let _ = app_handle.emit("passthrough-received", json!(entry)).inspect_err(|e| error!("Flow receiver error (passthrough): {e}"));
+        } else if let Flow::HandshakeFailure(failure) = &flow {
+            let _ = app_handle.emit("tls-handshake-failed", json!(failure)).inspect_err(|e| error!("Flow receiver error (handshake): {e}"));
         }
     }
 
@@ -209,18 +376,238 @@ pub async fn start_proxy(app_handle: AppHandle, state: Arc<AppState>) -> Result<
     Ok(())
 }
 
-async fn handle_client_connection(mut stream: TcpStream, issuer: Arc<Issuer<'static, KeyPair>>) -> io::Result<TlsStream<TcpStream>> {
+/// Reads requests off a client connection until it closes, forwarding each as a flow
+async fn serve_flows<S: AsyncRead + AsyncWrite + Resettable + Unpin>(tx: Arc<tokio::sync::mpsc::Sender<Flow>>, client_stream: &mut S, state: &Arc<AppState>, info: &ConnectionInfo) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    loop {
+        let req_raw = match read_http_request(client_stream).await {
+            Ok(r) => r,
+            Err(_) => break
+        };
+        let _ = handle_server_connection(tx.clone(), client_stream, req_raw, state, info).await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Protocol {
+    Tls,
+    Http,
+    Other,
+}
+
+/// Peeks at the first bytes a client sends through a tunnel without consuming them
+async fn sniff_protocol(stream: &TcpStream) -> io::Result<Protocol> {
+    let mut buf = [0u8; 8];
+    let n = match timeout(SNIFF_TIMEOUT, stream.peek(&mut buf)).await {
+        Ok(n) => n?,
+        Err(_) => return Ok(Protocol::Other),
+    };
+
+    if n >= 2 && buf[0] == 0x16 && buf[1] == 0x03 {
+        return Ok(Protocol::Tls)
+    }
+
+    // A request line starts with an uppercase method token followed by a space
+    let token = buf[..n].iter().take_while(|b| b.is_ascii_uppercase()).count();
+    if token > 0 && (token == n || buf[token] == b' ') {
+        return Ok(Protocol::Http)
+    }
+
+    Ok(Protocol::Other)
+}
+
+/// Peeks the complete first TLS record, which holds the ClientHello
+async fn peek_client_hello(stream: &TcpStream) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; 16 * 1024];
+    let peek = async {
+        loop {
+            let n = stream.peek(&mut buf).await?;
+            match handshake::record_len(&buf[..n]) {
+                Some(len) if n >= len || n == buf.len() => return Ok::<usize, io::Error>(n),
+                _ => sleep(Duration::from_millis(10)).await,
+            }
+        }
+    };
+
+    let n = timeout(SNIFF_TIMEOUT, peek).await.map_err(|_| {
+        io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for ClientHello")
+    })??;
+    buf.truncate(n);
+
+    Ok(buf)
+}
+
+/// Splits `host[:port]`, using the default port when none is given
+pub fn split_authority(authority: &str, default_port: u16) -> (String, u16) {
+    match authority.rsplit_once(":").and_then(|(host, port)| Some((host, port.parse().ok()?))) {
+        Some((host, port)) => (host.trim_matches(['[', ']']).to_string(), port),
+        None => (authority.to_string(), default_port),
+    }
+}
+
+async fn handle_socks_connection(mut stream: TcpStream, issuer: Arc<Issuer<'static, KeyPair>>, tx: Arc<tokio::sync::mpsc::Sender<Flow>>, state: Arc<AppState>) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let (host, port) = socks::accept(&mut stream).await?;
+    info!("SOCKS5 CONNECT to {host}:{port}");
+
+    handle_tunnel(stream, &host, port, issuer, tx, state).await
+}
+
+/// Handles an established CONNECT or SOCKS tunnel: passthrough hosts are relayed untouched,
+/// TLS is intercepted, plaintext HTTP is served directly and anything else is relayed.
+/// TLS connections are named by their SNI when there is one, as `host` is an IP for SOCKS clients resolving locally
+async fn handle_tunnel(mut stream: TcpStream, host: &str, port: u16, issuer: Arc<Issuer<'static, KeyPair>>, tx: Arc<tokio::sync::mpsc::Sender<Flow>>, state: Arc<AppState>) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    if state.passthrough.lock().await.matches(host, port) {
+        passthrough(stream, host, port, &tx, &state).await;
+        return Ok(())
+    }
+
+    let fallback = Some(match host.contains(':') {
+        true => format!("[{host}]:{port}"),
+        false => format!("{host}:{port}"),
+    });
+    match sniff_protocol(&stream).await? {
+        Protocol::Tls => {
+            let sni = peek_client_hello(&stream).await.ok().and_then(|hello| handshake::parse_sni(&hello));
+            let name = sni.as_deref().unwrap_or(host);
+            if name != host && state.passthrough.lock().await.matches(name, port) {
+                passthrough(stream, host, port, &tx, &state).await;
+                return Ok(())
+            }
+
+            let info = ConnectionInfo { fallback, ..ConnectionInfo::new("https") };
+            intercept_tls(stream, name, port, issuer, tx, state, info).await
+        },
+        Protocol::Http => {
+            let info = ConnectionInfo { fallback, ..ConnectionInfo::new("http") };
+            serve_flows(tx, &mut stream, &state, &info).await
+        },
+        Protocol::Other => {
+            relay(stream, host, port, &state).await?;
+            Ok(())
+        }
+    }
+}
+
+async fn handle_invisible_connection(mut stream: TcpStream, issuer: Arc<Issuer<'static, KeyPair>>, tx: Arc<tokio::sync::mpsc::Sender<Flow>>, state: Arc<AppState>, fallback: Option<String>) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    match sniff_protocol(&stream).await? {
+        Protocol::Tls => {
+            let hello = peek_client_hello(&stream).await?;
+            let domain = match handshake::parse_sni(&hello) {
+                Some(domain) => domain,
+                None => {
+                    let Some(fallback) = &fallback else {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "ClientHello without SNI and no fallback destination").into())
+                    };
+                    split_authority(fallback, 443).0
+                }
+            };
+
+            if state.passthrough.lock().await.matches(&domain, 443) {
+                passthrough(stream, &domain, 443, &tx, &state).await;
+                return Ok(())
+            }
+
+            info!("Invisible TLS connection for {domain}");
+            let info = ConnectionInfo { fallback, ..ConnectionInfo::new("https") };
+            intercept_tls(stream, &domain, 443, issuer, tx, state, info).await
+        },
+        Protocol::Http => {
+            let info = ConnectionInfo { fallback, ..ConnectionInfo::new("http") };
+            serve_flows(tx, &mut stream, &state, &info).await
+        },
+        Protocol::Other => {
+            let Some(fallback) = &fallback else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Unrecognised protocol and no fallback destination").into())
+            };
+            let (host, port) = split_authority(fallback, 80);
+            relay(stream, &host, port, &state).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Human readable cause of a failed client handshake, preferring the TLS alert the client sent
+fn handshake_failure_reason(e: &io::Error) -> String {
+    match e.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()) {
+        Some(rustls::Error::AlertReceived(alert)) => format!("Client sent alert: {:?}", alert),
+        Some(tls_error) => tls_error.to_string(),
+        None if e.kind() == io::ErrorKind::UnexpectedEof => "Client closed the connection during the handshake".to_string(),
+        None => e.to_string(),
+    }
+}
+
+/// MITMs the TLS connection and serves its requests, tracking hosts that reject our certificate
+async fn intercept_tls(stream: TcpStream, host: &str, port: u16, issuer: Arc<Issuer<'static, KeyPair>>, tx: Arc<tokio::sync::mpsc::Sender<Flow>>, state: Arc<AppState>, mut info: ConnectionInfo) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    info.client_hello = peek_client_hello(&stream).await.ok().and_then(|hello| handshake::parse_client_hello(&hello));
+
+    let mut tls_stream = match accept_tls(stream, host, issuer).await {
+        Ok(tls_stream) => tls_stream,
+        Err(e) => {
+            let reason = handshake_failure_reason(&e);
+            let (failures, passthrough_applied) = state.passthrough.lock().await.record_failure(host, port);
+            error!("TLS handshake with client for {host}:{port} failed ({failures} in a row): {reason}");
+
+            let failure = HandshakeFailure {
+                host: host.to_string(),
+                port,
+                reason,
+                failures,
+                passthrough_applied,
+            };
+            let _ = tx.send(Flow::HandshakeFailure(failure)).await;
+            return Err(e.into())
+        }
+    };
+    state.passthrough.lock().await.record_success(host);
+
+    serve_flows(tx, &mut tls_stream, &state, &info).await
+}
+
+/// Relays the tunnel without decrypting it and records its metadata in the flow history
+async fn passthrough(stream: TcpStream, host: &str, port: u16, tx: &tokio::sync::mpsc::Sender<Flow>, state: &AppState) {
+    info!("Passing through {host}:{port}");
+    let started = Instant::now();
+    let (bytes_sent, bytes_received, error) = match relay(stream, host, port, state).await {
+        Ok((sent, received)) => (sent, received, None),
+        Err(e) => (0, 0, Some(e.to_string())),
+    };
+
+    let entry = PassthroughEntry {
+        id: Uuid::new_v4().to_string(),
+        host: host.to_string(),
+        port,
+        bytes_sent,
+        bytes_received,
+        duration_ms: started.elapsed().as_millis(),
+        error,
+    };
+    let _ = tx.send(Flow::Passthrough(entry)).await;
+}
+
+/// Pipes raw bytes between the client and the target, returns bytes sent and received
+async fn relay(mut client_stream: TcpStream, host: &str, port: u16, state: &AppState) -> io::Result<(u64, u64)> {
+    let upstream = state.upstream.lock().await.clone();
+    let mut server_stream = upstream.connect(host, port).await?;
+    copy_bidirectional(&mut client_stream, &mut server_stream).await
+}
+
+async fn handle_client_connection(mut stream: TcpStream, issuer: Arc<Issuer<'static, KeyPair>>, tx: Arc<tokio::sync::mpsc::Sender<Flow>>, state: Arc<AppState>) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
     let req = read_request(&mut stream).await?;
     if !req.starts_with("CONNECT") {
-        return Err(io::Error::new(io::ErrorKind::Other, "Expected CONNECT"));
+        return Err(io::Error::new(io::ErrorKind::Other, "Expected CONNECT").into());
     }
 
+    let (domain, port) = split_authority(&get_domain(&req)?, 443);
+    stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+
+    handle_tunnel(stream, &domain, port, issuer, tx, state).await
+}
+
+/// Terminates the client's TLS with a certificate for the domain signed by our CA
+async fn accept_tls(stream: TcpStream, domain: &str, issuer: Arc<Issuer<'static, KeyPair>>) -> io::Result<TlsStream<TcpStream>> {
     // Generate cert and key-pair for domain
-    let domain_line = get_domain(&req)?;
-    let domain_line = domain_line.split(":").collect::<Vec<&str>>();
-    let domain = domain_line[0];
     let (cert, key) = generate_cert(domain.to_string(), issuer).await?;
-    stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
 
     let cert_der = cert.der();
     let key_der = key.serialize_der();
@@ -232,10 +619,10 @@ async fn handle_client_connection(mut stream: TcpStream, issuer: Arc<Issuer<'sta
     Ok(tls_stream)
 }
 
-async fn read_http_request(tls_stream: &mut TlsStream<TcpStream>) -> io::Result<String> {
+async fn read_http_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<String> {
     loop {
         let mut buf = vec![0u8; 4096];
-        let n = tls_stream.read(&mut buf[..]).await?;
+        let n = stream.read(&mut buf[..]).await?;
         if n == 0 {
             break;
         }
@@ -246,80 +633,24 @@ async fn read_http_request(tls_stream: &mut TlsStream<TcpStream>) -> io::Result<
     Ok("".to_string())
 }
 
-async fn forward_to_server(raw: String) -> io::Result<Response> {
-    let client = Client::new();
-    let raw_owned = raw.clone();
-
-    if let Some((headers_str, body)) = raw_owned.split_once("\r\n\r\n") {
-        let mut headers = HeaderMap::new();
-
-        let headers_split: Vec<String> = headers_str
-            .lines()
-            .skip(1)
-            .map(|line| line.to_string())
-            .collect();
-
-        let Some(request_line) = headers_str.lines().next() else {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed request data"))
-        };
-        let (method, path, _version) =
-            match request_line.split_whitespace().collect::<Vec<&str>>()[..] {
-                [m, p, v] => (m, p, v),
-                _ => {
-                    return Err(io::Error::new(io::ErrorKind::InvalidData, 
-                        format!("Malformed request line")))
-                }
-            };
-
-        let mut host = None;
-        for header in headers_split {
-            if let Some((key, value)) = header.split_once(":") {
-                if key.to_lowercase() == "host" {
-                    host = Some(value.trim().to_string());
-                }
-
-                let hname = HeaderName::from_bytes(key.trim().as_bytes()).map_err(|e|
-                    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid header data: {e}"))
-                )?;
-                let hvalue = HeaderValue::from_str(value.trim()).map_err(|e|
-                    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid header data: {e}"))
-                )?;
-                headers.insert(hname, hvalue);
-            }
-        }
-
-        headers.remove("accept-encoding");
-
-        let url = match host {
-            Some(host) => {
-                format!("https://{host}{path}")
-            }
-            None => path.to_string(),
-        };
-
-        let mut req = match method {
-            "GET" => client.get(url.clone()),
-            "POST" => client.post(url.clone()),
-            _ => {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, 
-                    format!("Invalid request method")))
-            },
-        };
-
-        req = req.headers(headers);
+async fn send_to_server(state: &AppState, raw: &str, target: &Target) -> io::Result<(Response<Bytes>, Option<UpstreamTls>)> {
+    let raw = signing::sign_raw(state, target, raw).await
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let (req, _) = client::build_request(&raw)?;
+    let conn = client::connect(state, target, None).await?;
+    let (res, _) = client::send(conn.stream, req).await?;
 
-        if method == "POST" {
-            req = req.body(body.to_string());
-        }
-
-        let res = req.send().await.map_err(|e| {
-            io::Error::new(io::ErrorKind::InvalidData, 
-                format!("Failed sending request to server: {}", e))
-        })?;
+    Ok((res, conn.tls))
+}
 
-        return Ok(res)
+/// Forwards the request with the host's session injected, retrying once after a re-login
+/// when the response shows the session expired
+async fn forward_to_server(state: &AppState, raw: String, target: &Target) -> io::Result<(Response<Bytes>, Option<UpstreamTls>)> {
+    let sent = Instant::now();
+    let (res, tls) = send_to_server(state, &session::inject(state, &target.host, &raw).await, target).await?;
+    if session::refresh_if_invalid(state, &target.host, res.status().as_u16(), &response_raw(&res), sent).await {
+        return send_to_server(state, &session::inject(state, &target.host, &raw).await, target).await
     }
 
-    Err(io::Error::new(io::ErrorKind::InvalidData, 
-        format!("Malformed request in forward_to_client")))
+    Ok((res, tls))
 }
@@ -0,0 +1,389 @@
+use std::{io, iter, pin::Pin, sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, task::{Context, Poll, ready}, time::{Duration, Instant}};
+
+use futures::future::join_all;
+use hyper::{Request, Response, body::Bytes, header};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tokio::{io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf}, sync::Barrier, time::{sleep, timeout}};
+use uuid::Uuid;
+
+use crate::{AppState, Res, client::{self, SendError, Target}, repeater::{RepeaterError, expand_request, prepare_raw, read_raw_response, res_from_raw, res_from_response, target_from_host}};
+
+/// Time for the pre-sent parts of every request to reach the server before the final bytes are released
+const WARMUP: Duration = Duration::from_millis(100);
+const RACE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Most requests one race may send, copies included. HTTP/1 races open a connection for each
+const MAX_RACE_REQUESTS: usize = 100;
+
+/// Size of a DATA frame without payload, as ending a stream takes
+const EMPTY_FRAME_LEN: usize = 9;
+
+/// Connection-specific headers HTTP/2 forbids
+const HOP_HEADERS: [header::HeaderName; 5] = [header::CONNECTION, header::TRANSFER_ENCODING, header::UPGRADE, header::TE, header::HOST];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RaceMode {
+    /// One connection per request, all but the last byte sent up front
+    Http1,
+    /// All requests on one connection, the final frames of every stream sent in a single write
+    Http2,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RaceResult {
+    pub index: usize,
+    pub request: String,
+    /// When the final bytes of this request went out, relative to the first request's
+    pub offset_us: u128,
+    pub response: Option<Res>,
+    pub error: Option<SendError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RaceReport {
+    pub id: String,
+    pub mode: RaceMode,
+    /// Gap between the first and last release
+    pub spread_us: u128,
+    pub results: Vec<RaceResult>,
+}
+
+/// Whether a `Corked` stream holds back its writes, and how many bytes it holds
+#[derive(Default)]
+struct Cork {
+    corked: AtomicBool,
+    held: AtomicUsize,
+}
+
+/// Stream holding back everything written while corked, written out in one go once uncorked
+struct Corked<S> {
+    inner: S,
+    cork: Arc<Cork>,
+    held: Vec<u8>,
+}
+
+impl<S: AsyncWrite + Unpin> Corked<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.held.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.held))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()))
+            }
+            self.held.drain(..n);
+        }
+        self.cork.held.store(0, Ordering::SeqCst);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Corked<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Corked<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.cork.corked.load(Ordering::SeqCst) {
+            self.held.extend_from_slice(buf);
+            self.cork.held.store(self.held.len(), Ordering::SeqCst);
+            return Poll::Ready(Ok(buf.len()))
+        }
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.cork.corked.load(Ordering::SeqCst) {
+            return Poll::Ready(Ok(()))
+        }
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Request bytes with a Content-Length matching the body, so holding back the last byte holds back the request
+fn frame_request(raw: &str) -> Vec<u8> {
+    let (head, body) = raw.split_once("\r\n\r\n").unwrap_or((raw.trim_end(), ""));
+    let method = head.split_whitespace().next().unwrap_or("");
+
+    let mut lines = head.split("\r\n")
+        .filter(|line| !line.split_once(":").is_some_and(|(key, _)| {
+            let key = key.trim();
+            key.eq_ignore_ascii_case("content-length") || key.eq_ignore_ascii_case("transfer-encoding")
+        }))
+        .map(str::to_string)
+        .collect::<Vec<String>>();
+    if !body.is_empty() || matches!(method, "POST" | "PUT" | "PATCH") {
+        lines.push(format!("Content-Length: {}", body.len()));
+    }
+
+    format!("{}\r\n\r\n{}", lines.join("\r\n"), body).into_bytes()
+}
+
+fn request_path(raw: &str) -> &str {
+    raw.split_whitespace().nth(1).unwrap_or("/")
+}
+
+async fn race_http1(state: &AppState, requests: &[String], target: &Target, sni: Option<&str>) -> Vec<(Option<Instant>, Result<Res, SendError>)> {
+    // Open every connection and send everything but the last byte
+    let prepared = join_all(requests.iter().map(|raw| async move {
+        let framed = frame_request(raw);
+        let mut conn = client::connect(state, target, sni).await?;
+        conn.stream.write_all(&framed[..framed.len() - 1]).await
+            .map_err(|e| SendError::Connect(format!("Failed writing request: {e}")))?;
+        conn.stream.flush().await
+            .map_err(|e| SendError::Connect(format!("Failed writing request: {e}")))?;
+        Ok::<_, SendError>((conn, framed[framed.len() - 1]))
+    })).await;
+    sleep(WARMUP).await;
+
+    let ready = prepared.iter().filter(|conn| conn.is_ok()).count();
+    let barrier = Arc::new(Barrier::new(ready));
+
+    let tasks = prepared.into_iter().zip(requests).map(|(conn, raw)| {
+        let barrier = barrier.clone();
+        let method = raw.split_whitespace().next().unwrap_or("").to_string();
+        let url = target.url(request_path(raw));
+
+        tokio::spawn(async move {
+            let (mut conn, last_byte) = match conn {
+                Ok(conn) => conn,
+                Err(e) => return (None, Err(e)),
+            };
+
+            barrier.wait().await;
+            if let Err(e) = conn.stream.write_all(&[last_byte]).await {
+                return (None, Err(SendError::Connect(format!("Failed releasing request: {e}"))))
+            }
+            let _ = conn.stream.flush().await;
+            let released = Instant::now();
+
+            let mut timings = conn.timings;
+            let response = match read_raw_response(&mut conn.stream, &method, released, &mut timings).await {
+                Ok(response) => response,
+                Err(e) => return (Some(released), Err(e)),
+            };
+            timings.total_ms = released.elapsed().as_millis();
+
+            (Some(released), Ok(res_from_raw(url, &response, timings, conn.tls)))
+        })
+    }).collect::<Vec<_>>();
+
+    join_all(tasks).await.into_iter()
+        .map(|result| result.unwrap_or_else(|e| (None, Err(SendError::Http(format!("Race task failed: {e}"))))))
+        .collect()
+}
+
+/// HTTP/2 form of a raw request. The Host header becomes the authority
+fn h2_request(raw: &str, target: &Target) -> Result<(Request<()>, Bytes), SendError> {
+    let (req, host) = client::build_request(raw)?;
+    let (parts, _) = req.into_parts();
+    let body = Bytes::from(raw.split_once("\r\n\r\n").map(|(_, body)| body).unwrap_or("").to_string());
+
+    let authority = host.unwrap_or(target.authority());
+    let path = parts.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/");
+    let mut builder = Request::builder()
+        .method(parts.method)
+        .uri(format!("{}://{}{}", target.scheme, authority, path));
+    for (name, value) in parts.headers.iter().filter(|(name, _)| !HOP_HEADERS.contains(name)) {
+        builder = builder.header(name, value);
+    }
+
+    let req = builder.body(()).map_err(|e| SendError::InvalidRequest(e.to_string()))?;
+    Ok((req, body))
+}
+
+async fn race_http2(state: &AppState, requests: &[String], target: &Target, sni: Option<&str>) -> Result<Vec<(Option<Instant>, Result<Res, SendError>)>, SendError> {
+    let h2_error = |e: h2::Error| SendError::Http(format!("HTTP/2 error: {e}"));
+    if target.scheme != "https" {
+        return Err(SendError::InvalidRequest("HTTP/2 races need an https target".to_string()))
+    }
+
+    let conn = client::connect_with_alpn(state, target, sni, &[b"h2"]).await?;
+    if conn.tls.as_ref().and_then(|tls| tls.alpn.as_deref()) != Some("h2") {
+        return Err(SendError::Http(format!("{} did not negotiate HTTP/2", target.authority())))
+    }
+    let tls = conn.tls.clone();
+    let timings = conn.timings.clone();
+
+    let cork = Arc::new(Cork::default());
+    let stream = Corked { inner: conn.stream, cork: cork.clone(), held: Vec::new() };
+    let (client, mut connection) = h2::client::handshake(stream).await.map_err(h2_error)?;
+
+    // The server's SETTINGS precede its answer to a ping, so its stream limit is known after the round trip.
+    // Streams beyond it would wait for earlier ones to finish, which can't happen while their ends are held back
+    let mut ping_pong = connection.ping_pong().ok_or(SendError::Http("HTTP/2 ping unavailable".to_string()))?;
+    tokio::select! {
+        result = &mut connection => {
+            return Err(SendError::Http(format!("HTTP/2 connection closed before the race: {:?}", result.err())))
+        },
+        pong = ping_pong.ping(h2::Ping::opaque()) => {
+            pong.map_err(h2_error)?;
+        },
+    }
+    let max_streams = connection.max_concurrent_send_streams();
+    if requests.len() > max_streams {
+        return Err(SendError::InvalidRequest(format!("{} allows {max_streams} concurrent streams, race at most that many requests", target.authority())))
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("HTTP/2 race connection error: {e}");
+        }
+    });
+
+    // Headers and bodies go out now, only the END_STREAM frames are held back
+    let mut client = client.ready().await.map_err(h2_error)?;
+    let mut streams = Vec::new();
+    for raw in requests {
+        let stream = match h2_request(raw, target) {
+            Ok((req, body)) => client.send_request(req, false).map_err(h2_error).and_then(|(response, mut stream)| {
+                if !body.is_empty() {
+                    stream.send_data(body, false).map_err(h2_error)?;
+                }
+                Ok((response, stream))
+            }),
+            Err(e) => Err(e),
+        };
+        streams.push(stream);
+        client = client.ready().await.map_err(h2_error)?;
+    }
+    sleep(WARMUP).await;
+
+    // Held back by the cork until every END_STREAM frame is written, then released in one write.
+    // Nothing else is queued on the connection by then, so a ping is what gets it writing again
+    cork.corked.store(true, Ordering::SeqCst);
+    let ended = streams.iter_mut()
+        .map(|stream| stream.as_mut().is_ok_and(|(_, stream)| stream.send_data(Bytes::new(), true).is_ok()))
+        .collect::<Vec<bool>>();
+    let expected = EMPTY_FRAME_LEN * ended.iter().filter(|ended| **ended).count();
+    let deadline = Instant::now() + WARMUP;
+    while cork.held.load(Ordering::SeqCst) < expected && Instant::now() < deadline {
+        sleep(Duration::from_millis(1)).await;
+    }
+    cork.corked.store(false, Ordering::SeqCst);
+    ping_pong.send_ping(h2::Ping::opaque()).map_err(h2_error)?;
+    let now = Instant::now();
+    let released = ended.into_iter().map(|ended| ended.then_some(now)).collect::<Vec<Option<Instant>>>();
+
+    let responses = streams.into_iter().zip(requests).zip(released.clone()).map(|((stream, raw), released)| {
+        let url = target.url(request_path(raw));
+        let tls = tls.clone();
+        let mut timings = timings.clone();
+
+        async move {
+            let (response, _) = stream?;
+            let started = released.unwrap_or(Instant::now());
+            let response = response.await.map_err(h2_error)?;
+            timings.ttfb_ms = started.elapsed().as_millis();
+
+            let (parts, mut body) = response.into_parts();
+            let mut data = Vec::new();
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk.map_err(h2_error)?;
+                let _ = body.flow_control().release_capacity(chunk.len());
+                data.extend_from_slice(&chunk);
+            }
+            timings.total_ms = started.elapsed().as_millis();
+
+            let response = Response::from_parts(parts, Bytes::from(data));
+            Ok(res_from_response(url, &response, timings, tls))
+        }
+    });
+
+    let results = join_all(responses).await;
+    Ok(released.into_iter().zip(results).collect())
+}
+
+/// How a race is sent, apart from its requests
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RaceOptions {
+    /// Times each request is sent, once by default
+    #[serde(default)]
+    pub copies: Option<usize>,
+    /// Where to connect, the first request's Host header when not given
+    #[serde(default)]
+    pub target: Option<Target>,
+    #[serde(default)]
+    pub sni: Option<String>,
+    /// Environment for the placeholders, the active one when not given
+    #[serde(default)]
+    pub environment: Option<String>,
+    #[serde(default)]
+    pub use_cookie_jar: bool,
+}
+
+/// Sends every request, each repeated as many times as `options` asks, so they arrive at the server as close together
+/// as possible. Requests are expanded from the environment and get the cookie jar, session and signature
+/// like Repeater sends before any of them go out. Results are in send order with each release offset
+/// from the earliest one
+#[tauri::command]
+pub async fn send_race(state: State<'_, Arc<AppState>>, id: Option<String>, requests: Vec<String>, mode: RaceMode, options: Option<RaceOptions>) -> Result<RaceReport, RepeaterError> {
+    let RaceOptions { copies, target, sni, environment, use_cookie_jar } = options.unwrap_or_default();
+    let id = id.unwrap_or(Uuid::new_v4().to_string());
+    let fail = |error: SendError| RepeaterError { id: id.clone(), error };
+
+    let Some(first) = requests.first() else {
+        return Err(fail(SendError::InvalidRequest("No requests to race".to_string())))
+    };
+    let copies = copies.unwrap_or(1).max(1);
+    if requests.len().checked_mul(copies).is_none_or(|total| total > MAX_RACE_REQUESTS) {
+        return Err(fail(SendError::InvalidRequest(format!("Race would send more than {MAX_RACE_REQUESTS} requests"))))
+    }
+    let first = expand_request(&state, first, environment.as_deref()).await.map_err(fail)?;
+    let target = match target {
+        Some(target) => target,
+        None => target_from_host(client::build_request(&first).map_err(fail)?.1).map_err(fail)?,
+    };
+
+    let mut prepared = Vec::new();
+    for raw in &requests {
+        let raw = expand_request(&state, raw, environment.as_deref()).await.map_err(fail)?;
+        prepared.push(prepare_raw(&state, &raw, &target, use_cookie_jar).await.map_err(fail)?);
+    }
+    let requests = prepared.iter()
+        .flat_map(|raw| iter::repeat(raw.clone()).take(copies))
+        .collect::<Vec<String>>();
+
+    info!("Racing {} requests to {} over {:?}", requests.len(), target.authority(), mode);
+    let race = async {
+        match mode {
+            RaceMode::Http1 => Ok(race_http1(&state, &requests, &target, sni.as_deref()).await),
+            RaceMode::Http2 => race_http2(&state, &requests, &target, sni.as_deref()).await,
+        }
+    };
+    let outcomes = match timeout(RACE_TIMEOUT, race).await {
+        Ok(outcomes) => outcomes.map_err(fail)?,
+        Err(_) => return Err(fail(SendError::Timeout(format!("Race did not finish within {}s", RACE_TIMEOUT.as_secs())))),
+    };
+
+    let first_release = outcomes.iter().filter_map(|(released, _)| *released).min();
+    let last_release = outcomes.iter().filter_map(|(released, _)| *released).max();
+    let offset = |released: Option<Instant>| match (released, first_release) {
+        (Some(released), Some(first)) => released.duration_since(first).as_micros(),
+        _ => 0,
+    };
+
+    let results = outcomes.into_iter().zip(requests).enumerate().map(|(index, ((released, result), request))| RaceResult {
+        index,
+        request,
+        offset_us: offset(released),
+        response: result.as_ref().ok().cloned(),
+        error: result.err(),
+    }).collect();
+
+    Ok(RaceReport {
+        id: id.clone(),
+        mode,
+        spread_us: offset(last_release),
+        results,
+    })
+}
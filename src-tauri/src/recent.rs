@@ -0,0 +1,33 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Map keeping only the most recently inserted `capacity` entries, for per-flow details that
+/// would otherwise grow for as long as the proxy runs
+#[derive(Debug)]
+pub struct Recent<V> {
+    capacity: usize,
+    entries: HashMap<String, V>,
+    /// Keys oldest first
+    order: VecDeque<String>,
+}
+
+impl<V> Recent<V> {
+    pub fn new(capacity: usize) -> Self {
+        Recent { capacity, entries: HashMap::new(), order: VecDeque::new() }
+    }
+
+    /// Inserts or replaces the entry, evicting the oldest ones past the capacity
+    pub fn insert(&mut self, key: String, value: V) {
+        if self.entries.insert(key.clone(), value).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.entries.get(key)
+    }
+}
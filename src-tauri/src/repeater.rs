@@ -0,0 +1,525 @@
+use std::{future::Future, sync::Arc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use hyper::{Response, body::Bytes};
+use log::info;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, time::timeout};
+use uuid::Uuid;
+
+use crate::{AppState, Res, client::{self, SendError, Target, Timings}, cookies, environments, handshake::UpstreamTls, proxy::split_authority, session, signing};
+
+/// Repeater requests without a response by then fail with a timeout error
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// A raw-mode response that can't be framed is considered done once the server goes quiet this long
+const RAW_IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A failed send, tagged with the request it belongs to
+#[derive(Debug, Serialize)]
+pub struct RepeaterError {
+    pub id: String,
+    #[serde(flatten)]
+    pub error: SendError,
+}
+
+/// Target named by the Host header, over https unless a port says otherwise
+pub fn target_from_host(host: Option<String>) -> Result<Target, SendError> {
+    let Some(authority) = host else {
+        return Err(SendError::InvalidRequest("Missing Host header and no explicit target".to_string()))
+    };
+    let (host, port) = split_authority(&authority, 443);
+    let scheme = if port == 80 { "http" } else { "https" };
+    Ok(Target { scheme: scheme.to_string(), host, port })
+}
+
+/// A redirect response that was followed, with the request that got it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Hop {
+    pub request: String,
+    pub response: Res,
+}
+
+/// Request for the next hop of a redirect: 303, and 301/302 after anything but GET or HEAD,
+/// switch to a bodiless GET. Everything else is resent with the Host header pointed at the new target.
+/// Credentials are dropped when the redirect leaves the origin of `from`
+fn redirect_request(raw: &str, status: u16, from: &Target, target: &Target, path: &str) -> String {
+    let (head, body) = raw.split_once("\r\n\r\n").unwrap_or((raw, ""));
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("GET");
+    let version = request_line.nth(1).unwrap_or("HTTP/1.1");
+
+    let to_get = status == 303 || ((status == 301 || status == 302) && method != "GET" && method != "HEAD");
+    let method = if to_get { "GET" } else { method };
+    let body = if to_get { "" } else { body };
+
+    let authority = target.authority();
+    let cross_origin = from != target;
+    let headers = lines.filter_map(|line| match line.split_once(":") {
+        Some((key, _)) if key.trim().eq_ignore_ascii_case("host") => Some(format!("{key}: {authority}")),
+        Some((key, _)) if to_get && key.trim().eq_ignore_ascii_case("content-type") => None,
+        Some((key, _)) if cross_origin && ["authorization", "proxy-authorization", "cookie"].iter().any(|name| key.trim().eq_ignore_ascii_case(name)) => None,
+        _ => Some(line.to_string()),
+    }).collect::<Vec<String>>();
+
+    let head = std::iter::once(format!("{method} {path} {version}")).chain(headers).collect::<Vec<String>>().join("\r\n");
+    format!("{head}\r\n\r\n{body}")
+}
+
+/// Where a redirect response points, resolved against the URL that returned it
+fn redirect_target(res: &Res) -> Option<(Target, String)> {
+    if !matches!(res.status.split_whitespace().next(), Some("301" | "302" | "303" | "307" | "308")) {
+        return None
+    }
+    let location = res.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("location")).map(|(_, v)| v)?;
+    let next = Url::parse(&res.url).ok()?.join(location).ok()?;
+
+    let scheme = next.scheme().to_string();
+    if scheme != "http" && scheme != "https" {
+        return None
+    }
+    let target = Target {
+        host: next.host_str()?.trim_matches(['[', ']']).to_string(),
+        port: next.port_or_known_default()?,
+        scheme,
+    };
+    let path = match next.query() {
+        Some(query) => format!("{}?{}", next.path(), query),
+        None => next.path().to_string(),
+    };
+
+    Some((target, path))
+}
+
+/// Sends the request and follows up to `follow_redirects` redirects. Each followed redirect is kept
+/// in `hops` of the final response with the request as it was sent. The SNI override only applies while the host stays the same
+pub async fn send_parsed(state: &AppState, raw: &str, target: Option<Target>, sni: Option<&str>, follow_redirects: u32, use_cookie_jar: bool) -> Result<Res, SendError> {
+    let (_, host) = client::build_request(raw)?;
+    let mut target = match target {
+        Some(target) => target,
+        None => target_from_host(host)?,
+    };
+    let first_host = target.host.clone();
+    let mut raw = raw.to_string();
+    let mut hops = Vec::new();
+
+    loop {
+        let sni = sni.filter(|_| target.host == first_host);
+        let sent = Instant::now();
+        let mut prepared = prepare_raw(state, &raw, &target, use_cookie_jar).await?;
+        let mut res = send_prepared(state, &prepared, &target, sni).await?;
+        if session::refresh_if_invalid(state, &target.host, response_status(&res), &res.raw, sent).await {
+            prepared = prepare_raw(state, &raw, &target, use_cookie_jar).await?;
+            res = send_prepared(state, &prepared, &target, sni).await?;
+        }
+
+        let next = redirect_target(&res).filter(|_| hops.len() < follow_redirects as usize);
+        let Some((next_target, path)) = next else {
+            return Ok(Res { hops, ..res })
+        };
+
+        info!("Following redirect to {}", next_target.url(&path));
+        raw = redirect_request(&raw, response_status(&res), &target, &next_target, &path);
+        hops.push(Hop { request: prepared, response: res });
+        target = next_target;
+    }
+}
+
+/// The request as `send_parsed` would send it to `target`: with the jar's cookies when asked for,
+/// the host's session and its signature. For tools that write the request out themselves
+pub async fn prepare_raw(state: &AppState, raw: &str, target: &Target, use_cookie_jar: bool) -> Result<String, SendError> {
+    let raw = match use_cookie_jar {
+        true => cookies::inject_jar(state, target, raw).await,
+        false => raw.to_string(),
+    };
+    let raw = session::inject(state, &target.host, &raw).await;
+    signing::sign_raw(state, target, &raw).await.map_err(SendError::InvalidRequest)
+}
+
+fn response_status(res: &Res) -> u16 {
+    res.status.split_whitespace().next().and_then(|s| s.parse().ok()).unwrap_or(0)
+}
+
+/// Sends the request once, signed by the host's signing profile but without session handling
+pub async fn send_once(state: &AppState, raw: &str, target: &Target, sni: Option<&str>) -> Result<Res, SendError> {
+    let raw = signing::sign_raw(state, target, raw).await.map_err(SendError::InvalidRequest)?;
+    send_prepared(state, &raw, target, sni).await
+}
+
+/// Sends the request once exactly as given
+async fn send_prepared(state: &AppState, raw: &str, target: &Target, sni: Option<&str>) -> Result<Res, SendError> {
+    let started = Instant::now();
+    let (req, _) = client::build_request(raw)?;
+    if target.scheme != "http" && target.scheme != "https" {
+        return Err(SendError::InvalidRequest(format!("Unsupported scheme {}", target.scheme)))
+    }
+    let url = target.url(&req.uri().to_string());
+
+    info!("Sending to {} {}", req.method(), url);
+    let conn = client::connect(state, target, sni).await?;
+    let mut timings = conn.timings;
+    let (res, ttfb) = client::send(conn.stream, req).await?;
+    timings.ttfb_ms = ttfb.as_millis();
+    timings.total_ms = started.elapsed().as_millis();
+
+    Ok(res_from_response(url, &res, timings, conn.tls))
+}
+
+pub fn res_from_response(url: String, res: &Response<Bytes>, timings: Timings, tls: Option<UpstreamTls>) -> Res {
+    let status = res.status().to_string();
+    let headers = res.headers()
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+        .collect::<Vec<(String, String)>>();
+    let body = String::from_utf8_lossy(res.body()).to_string();
+    let raw = [format!("HTTP/1.1 {}", status), headers.iter().map(|(k, v)| format!("{}: {}", k, v)).collect::<Vec<String>>().join("\r\n"), "".to_string(), body.clone()].join("\r\n");
+
+    Res {
+        url,
+        status,
+        headers,
+        size: res.body().len(),
+        body,
+        raw,
+        timings,
+        tls,
+        ..Default::default()
+    }
+}
+
+/// Reads a raw response until its framing says it is done, the server closes, or it goes quiet.
+/// Sets the TTFB relative to `sent`
+pub async fn read_raw_response(stream: &mut Box<dyn client::Stream>, method: &str, sent: Instant, timings: &mut Timings) -> Result<Vec<u8>, SendError> {
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 8192];
+    // Start of the body and how it ends, once the whole head arrived
+    let mut framing: Option<(usize, Framing)> = None;
+    loop {
+        let n = match timeout(RAW_IDLE_TIMEOUT, stream.read(&mut buffer)).await {
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) if response.is_empty() => return Err(SendError::Http(format!("Failed reading response: {e}"))),
+            // Servers often close without a TLS close_notify, keep what arrived
+            Ok(Err(_)) => break,
+            Err(_) if response.is_empty() => return Err(SendError::Timeout(format!("No response within {}s", RAW_IDLE_TIMEOUT.as_secs()))),
+            Err(_) => break,
+        };
+        if n == 0 {
+            break;
+        }
+        if response.is_empty() {
+            timings.ttfb_ms = sent.elapsed().as_millis();
+        }
+        let searched = response.len();
+        response.extend_from_slice(&buffer[..n]);
+        if framing.is_none() {
+            framing = head_end(&response, searched).map(|body_start| (body_start, Framing::parse(&response[..body_start], method)));
+        }
+        if framing.as_ref().is_some_and(|(body_start, framing)| framing.complete(&response[*body_start..])) {
+            break;
+        }
+    }
+
+    Ok(response)
+}
+
+/// Result for a response read off the socket as is, `size` counts all of it
+pub fn res_from_raw(url: String, response: &[u8], timings: Timings, tls: Option<UpstreamTls>) -> Res {
+    let raw = String::from_utf8_lossy(response).to_string();
+    let (head, body) = raw.split_once("\r\n\r\n").or(raw.split_once("\n\n")).unwrap_or((raw.as_str(), ""));
+    let mut lines = head.lines();
+    let status = lines.next()
+        .and_then(|line| line.split_once(" "))
+        .map(|(_, status)| status.trim().to_string())
+        .unwrap_or_default();
+    let headers = lines
+        .filter_map(|line| line.split_once(":"))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect::<Vec<(String, String)>>();
+
+    Res {
+        url,
+        status,
+        headers,
+        size: response.len(),
+        body: body.to_string(),
+        raw: raw.clone(),
+        timings,
+        tls,
+        ..Default::default()
+    }
+}
+
+/// Where a response ends according to its head
+enum Framing {
+    /// No body follows the head
+    Empty,
+    Length(usize),
+    Chunked,
+    /// Runs until the server closes
+    Close,
+}
+
+impl Framing {
+    fn parse(head: &[u8], method: &str) -> Self {
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.lines();
+        let status = lines.next().and_then(|line| line.split_whitespace().nth(1)).unwrap_or("");
+        if method.eq_ignore_ascii_case("HEAD") || status.starts_with('1') || status == "204" || status == "304" {
+            return Framing::Empty
+        }
+
+        let mut content_length = None;
+        let mut chunked = false;
+        for (key, value) in lines.filter_map(|line| line.split_once(":")) {
+            match key.trim().to_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse::<usize>().ok(),
+                "transfer-encoding" => chunked = value.to_lowercase().contains("chunked"),
+                _ => {}
+            }
+        }
+
+        match (chunked, content_length) {
+            (true, _) => Framing::Chunked,
+            (false, Some(length)) => Framing::Length(length),
+            (false, None) => Framing::Close,
+        }
+    }
+
+    /// Whether `body`, everything after the head so far, is the whole body
+    fn complete(&self, body: &[u8]) -> bool {
+        match self {
+            Framing::Empty => true,
+            Framing::Length(length) => body.len() >= *length,
+            Framing::Chunked => body.ends_with(b"0\r\n\r\n"),
+            Framing::Close => false,
+        }
+    }
+}
+
+/// Offset just past the blank line ending the head, looking only at bytes from around `from` on
+fn head_end(buf: &[u8], from: usize) -> Option<usize> {
+    (from.saturating_sub(3)..buf.len()).find_map(|i| match &buf[i..] {
+        rest if rest.starts_with(b"\r\n\r\n") => Some(i + 4),
+        rest if rest.starts_with(b"\n\n") => Some(i + 2),
+        _ => None,
+    })
+}
+
+/// Target named by a Host header found anywhere in the unparsed request
+fn raw_target(raw: &str) -> Result<Target, SendError> {
+    let host = raw.lines()
+        .skip(1)
+        .take_while(|line| !line.trim().is_empty())
+        .filter_map(|line| line.split_once(":"))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim().to_string());
+    target_from_host(host)
+}
+
+/// Writes the request bytes exactly as given and reads back whatever the server answers
+async fn send_raw_socket(state: &AppState, raw: &str, target: Option<Target>, sni: Option<&str>) -> Result<Res, SendError> {
+    let started = Instant::now();
+    let target = match target {
+        Some(target) => target,
+        None => raw_target(raw)?,
+    };
+    let mut request_line = raw.lines().next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let url = target.url(request_line.next().unwrap_or(""));
+
+    info!("Sending raw bytes to {url}");
+    let mut conn = client::connect(state, &target, sni).await?;
+    let mut timings = conn.timings;
+
+    conn.stream.write_all(raw.as_bytes()).await
+        .map_err(|e| SendError::Connect(format!("Failed writing request: {e}")))?;
+    conn.stream.flush().await
+        .map_err(|e| SendError::Connect(format!("Failed writing request: {e}")))?;
+    let sent = Instant::now();
+
+    let response = read_raw_response(&mut conn.stream, method, sent, &mut timings).await?;
+    timings.total_ms = started.elapsed().as_millis();
+
+    Ok(res_from_raw(url, &response, timings, conn.tls))
+}
+
+/// A Repeater send as it was made, kept in the project per tab
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepeaterEntry {
+    pub id: String,
+    pub tab: String,
+    /// Unix time in milliseconds
+    pub sent_at: u128,
+    /// Request as sent, placeholders expanded
+    pub request: String,
+    /// Request as written when it had placeholders
+    #[serde(default)]
+    pub template: Option<String>,
+    pub target: Option<Target>,
+    pub sni: Option<String>,
+    pub raw_mode: bool,
+    pub follow_redirects: u32,
+    /// Cookies from the project cookie jar were added
+    #[serde(default)]
+    pub use_cookie_jar: bool,
+    pub response: Option<Res>,
+    pub error: Option<SendError>,
+}
+
+impl RepeaterEntry {
+    fn new(tab: String, template: &str, request: &str, target: &Option<Target>, sni: &Option<String>, raw_mode: bool, follow_redirects: u32) -> Self {
+        RepeaterEntry {
+            id: String::new(),
+            tab,
+            sent_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0),
+            request: request.to_string(),
+            template: (template != request).then(|| template.to_string()),
+            target: target.clone(),
+            sni: sni.clone(),
+            raw_mode,
+            follow_redirects,
+            use_cookie_jar: false,
+            response: None,
+            error: None,
+        }
+    }
+}
+
+/// History of one Repeater tab with the entry back/forward navigation is on
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RepeaterTab {
+    pub entries: Vec<RepeaterEntry>,
+    pub cursor: Option<usize>,
+}
+
+/// Tags the outcome of a send with the Repeater request id and records it in the tab's history
+pub async fn tagged(state: &AppState, id: Option<String>, entry: Option<RepeaterEntry>, send: impl Future<Output = Result<Res, SendError>>) -> Result<Res, RepeaterError> {
+    let id = id.unwrap_or(Uuid::new_v4().to_string());
+
+    let result = match timeout(REQUEST_TIMEOUT, send).await {
+        Ok(Ok(res)) => Ok(Res { id: id.clone(), ..res }),
+        Ok(Err(error)) => Err(error),
+        Err(_) => Err(SendError::Timeout(format!("No response within {}s", REQUEST_TIMEOUT.as_secs()))),
+    };
+
+    if let Some(entry) = entry {
+        let entry = RepeaterEntry {
+            id: id.clone(),
+            response: result.as_ref().ok().cloned(),
+            error: result.as_ref().err().cloned(),
+            ..entry
+        };
+
+        let mut project = state.project.lock().await;
+        let tab = project.data.repeater.entry(entry.tab.clone()).or_default();
+        tab.entries.push(entry);
+        tab.cursor = Some(tab.entries.len() - 1);
+        project.mark_dirty();
+    }
+
+    result.map_err(|error| RepeaterError { id, error })
+}
+
+/// Expands the placeholders in a Repeater request with the named environment, or the active one
+pub async fn expand_request(state: &AppState, raw: &str, environment: Option<&str>) -> Result<String, SendError> {
+    let variables = state.project.lock().await.data.environments.variables(environment)
+        .map_err(SendError::InvalidRequest)?;
+    environments::expand(raw, &variables).map_err(SendError::InvalidRequest)
+}
+
+/// How a Repeater request is sent, apart from the request itself
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SendOptions {
+    /// Where to connect, the Host header's authority when not given
+    #[serde(default)]
+    pub target: Option<Target>,
+    #[serde(default)]
+    pub sni: Option<String>,
+    /// Redirects to follow at most, none by default
+    #[serde(default)]
+    pub follow_redirects: u32,
+    /// Environment for the placeholders, the active one when not given
+    #[serde(default)]
+    pub environment: Option<String>,
+    /// Add the project's cookies for the target to the Cookie header
+    #[serde(default)]
+    pub use_cookie_jar: bool,
+}
+
+/// Sends a raw request from a Repeater tab. `id` is echoed back on the result or error so
+/// tabs can tell their responses apart, one is generated when not given.
+/// The target and SNI in `options` are independent of the Host header in `raw`, which is sent as written
+/// after its placeholders are expanded. Redirects are returned as is unless `options` allows following some.
+/// Sends from a `tab` are kept in its history
+#[tauri::command]
+pub async fn send_request(state: State<'_, Arc<AppState>>, id: Option<String>, tab: Option<String>, raw: String, options: Option<SendOptions>) -> Result<Res, RepeaterError> {
+    let SendOptions { target, sni, follow_redirects, environment, use_cookie_jar } = options.unwrap_or_default();
+    let expanded = expand_request(&state, &raw, environment.as_deref()).await;
+    let request = expanded.as_deref().unwrap_or(&raw);
+    let entry = tab.map(|tab| RepeaterEntry { use_cookie_jar, ..RepeaterEntry::new(tab, &raw, request, &target, &sni, false, follow_redirects) });
+    let send = async {
+        send_parsed(&state, &expanded.clone()?, target.clone(), sni.as_deref(), follow_redirects, use_cookie_jar).await
+    };
+    tagged(&state, id, entry, send).await
+}
+
+/// Raw mode: the request is written byte for byte with no parsing or normalization, so malformed
+/// framing, bare LF and odd request lines reach the server. Only placeholders are expanded.
+/// `size` counts the whole raw response
+#[tauri::command]
+pub async fn send_raw_request(state: State<'_, Arc<AppState>>, id: Option<String>, tab: Option<String>, raw: String, target: Option<Target>, sni: Option<String>, environment: Option<String>) -> Result<Res, RepeaterError> {
+    let expanded = expand_request(&state, &raw, environment.as_deref()).await;
+    let request = expanded.as_deref().unwrap_or(&raw);
+    let entry = tab.map(|tab| RepeaterEntry::new(tab, &raw, request, &target, &sni, true, 0));
+    let send = async {
+        send_raw_socket(&state, &expanded.clone()?, target.clone(), sni.as_deref()).await
+    };
+    tagged(&state, id, entry, send).await
+}
+
+/// Entries of a tab, oldest first
+#[tauri::command]
+pub async fn list_repeater_history(state: State<'_, Arc<AppState>>, tab: String) -> Result<Vec<RepeaterEntry>, String> {
+    let project = state.project.lock().await;
+    Ok(project.data.repeater.get(&tab).map(|tab| tab.entries.clone()).unwrap_or_default())
+}
+
+#[tauri::command]
+pub async fn get_repeater_entry(state: State<'_, Arc<AppState>>, id: String) -> Result<RepeaterEntry, String> {
+    let project = state.project.lock().await;
+    project.data.repeater.values()
+        .flat_map(|tab| tab.entries.iter())
+        .find(|entry| entry.id == id)
+        .cloned()
+        .ok_or(format!("No Repeater entry {id}"))
+}
+
+/// Moves the tab's cursor by `step` entries and returns the entry it lands on.
+/// The cursor stops at either end of the history
+async fn navigate(state: &AppState, tab: &str, step: isize) -> Result<Option<RepeaterEntry>, String> {
+    let mut project = state.project.lock().await;
+    let Some(history) = project.data.repeater.get_mut(tab) else {
+        return Ok(None)
+    };
+    let Some(cursor) = history.cursor else {
+        return Ok(None)
+    };
+
+    let cursor = cursor.saturating_add_signed(step).min(history.entries.len().saturating_sub(1));
+    history.cursor = Some(cursor);
+    let entry = history.entries.get(cursor).cloned();
+
+    project.mark_dirty();
+    Ok(entry)
+}
+
+#[tauri::command]
+pub async fn repeater_back(state: State<'_, Arc<AppState>>, tab: String) -> Result<Option<RepeaterEntry>, String> {
+    navigate(&state, &tab, -1).await
+}
+
+#[tauri::command]
+pub async fn repeater_forward(state: State<'_, Arc<AppState>>, tab: String) -> Result<Option<RepeaterEntry>, String> {
+    navigate(&state, &tab, 1).await
+}
@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use log::info;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+use crate::{AppState, network::HostScoped};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleTarget {
+    Request,
+    Response,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleLocation {
+    /// Request line or status line
+    FirstLine,
+    /// Each `Name: value` line on its own. A line replaced with nothing is removed
+    Header,
+    Body,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchKind {
+    Literal,
+    /// Replacement can refer to capture groups as `$1` or `${name}`
+    Regex,
+}
+
+/// Declarative match-and-replace applied to proxied traffic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplaceRule {
+    pub name: String,
+    pub enabled: bool,
+    pub target: RuleTarget,
+    pub location: RuleLocation,
+    pub kind: MatchKind,
+    pub pattern: String,
+    #[serde(default)]
+    pub replacement: String,
+    /// Host glob the rule is scoped to, all hosts when empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// `pattern` compiled when the rule is set, for regex rules
+    #[serde(skip)]
+    regex: Option<Regex>,
+}
+
+impl HostScoped for ReplaceRule {
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+}
+
+impl ReplaceRule {
+    fn applies(&self, target: RuleTarget, host: &str) -> bool {
+        self.target == target && self.applies_to(host)
+    }
+
+    /// Checks the rule and compiles its pattern
+    fn compile(mut self) -> Result<Self, String> {
+        if self.kind == MatchKind::Regex {
+            self.regex = Some(Regex::new(&self.pattern).map_err(|e| format!("Invalid pattern in rule {}: {e}", self.name))?);
+        }
+        Ok(self)
+    }
+
+    /// Replaced text, or None when the pattern does not occur
+    fn replace(&self, text: &str) -> Option<String> {
+        match &self.regex {
+            Some(regex) => regex.is_match(text).then(|| regex.replace_all(text, self.replacement.as_str()).to_string()),
+            None => (!self.pattern.is_empty() && text.contains(&self.pattern)).then(|| text.replace(&self.pattern, &self.replacement)),
+        }
+    }
+}
+
+/// Runs the enabled rules for `target` and `host` over a raw HTTP message in order.
+/// Returns the rewritten message and the names of the rules that fired
+pub fn apply_rules(rules: &[ReplaceRule], target: RuleTarget, host: &str, raw: &str) -> (String, Vec<String>) {
+    let (head, body) = raw.split_once("\r\n\r\n").unwrap_or((raw, ""));
+    let mut lines = head.split("\r\n").map(str::to_string);
+    let mut first_line = lines.next().unwrap_or_default();
+    let mut headers = lines.collect::<Vec<String>>();
+    let mut body = body.to_string();
+    let mut body_changed = false;
+    let mut fired = Vec::new();
+
+    for rule in rules.iter().filter(|rule| rule.applies(target, host)) {
+        let matched = match rule.location {
+            RuleLocation::FirstLine => rule.replace(&first_line).map(|line| first_line = line).is_some(),
+            RuleLocation::Header => {
+                let mut matched = false;
+                headers = headers.into_iter().filter_map(|line| match rule.replace(&line) {
+                    Some(replaced) => {
+                        matched = true;
+                        (!replaced.trim().is_empty()).then_some(replaced)
+                    },
+                    None => Some(line),
+                }).collect();
+                matched
+            },
+            RuleLocation::Body => {
+                let replaced = rule.replace(&body).map(|replaced| body = replaced).is_some();
+                body_changed |= replaced;
+                replaced
+            },
+        };
+
+        if matched {
+            fired.push(rule.name.clone());
+        }
+    }
+
+    if body_changed {
+        for line in headers.iter_mut() {
+            if let Some((name, _)) = line.split_once(":") {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    *line = format!("{}: {}", name, body.len());
+                }
+            }
+        }
+    }
+
+    if fired.is_empty() {
+        return (raw.to_string(), fired)
+    }
+
+    let head = std::iter::once(first_line).chain(headers).collect::<Vec<String>>().join("\r\n");
+    (format!("{head}\r\n\r\n{body}"), fired)
+}
+
+#[tauri::command]
+pub async fn get_replace_rules(state: State<'_, Arc<AppState>>) -> Result<Vec<ReplaceRule>, String> {
+    Ok(state.replace_rules.lock().await.clone())
+}
+
+#[tauri::command]
+pub async fn set_replace_rules(state: State<'_, Arc<AppState>>, rules: Vec<ReplaceRule>) -> Result<(), String> {
+    let rules = rules.into_iter().map(ReplaceRule::compile).collect::<Result<Vec<ReplaceRule>, String>>()?;
+
+    info!("Updated match-and-replace rules: {}", rules.len());
+    *state.replace_rules.lock().await = rules;
+    Ok(())
+}
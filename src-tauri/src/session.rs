@@ -0,0 +1,318 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use log::{error, info};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::State;
+use tokio::time::timeout;
+
+use crate::{AppState, client::{self, SendError, Target}, cookies::{cookie_header, merge_cookie_header}, environments, network::{HostScoped, select_scoped}, repeater::{send_once, target_from_host}};
+
+/// Longest a re-login macro may take before it is given up on
+const MACRO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Response that shows the session is no longer valid
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InvalidSession {
+    /// Statuses that mean the session expired, such as 401 or a 302 to the login page
+    #[serde(default)]
+    pub statuses: Vec<u16>,
+    /// Regex matched against the raw response
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// `pattern` compiled when the rule is set
+    #[serde(skip)]
+    regex: Option<Regex>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExtractKind {
+    /// First capture group, or the whole match, in the raw response
+    Regex { pattern: String },
+    /// Dotted path into a JSON body, such as `$.data.tokens[0].value`
+    Json { path: String },
+}
+
+/// Value pulled out of a macro response, available to later steps and injections as `{{env.name}}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Extractor {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: ExtractKind,
+}
+
+/// One request of a re-login macro. Placeholders are expanded with the values extracted so far
+/// and cookies set by earlier steps are sent along
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MacroStep {
+    pub request: String,
+    /// Where to send the request, from its Host header when not set
+    #[serde(default)]
+    pub target: Option<Target>,
+    #[serde(default)]
+    pub extract: Vec<Extractor>,
+}
+
+/// Header set on every request to the rule's hosts, with `{{env.name}}` placeholders for extracted values
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Injection {
+    pub header: String,
+    pub value: String,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Logs in again with a recorded macro when a response to a matching host shows the session expired
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRule {
+    pub name: String,
+    pub enabled: bool,
+    /// Host globs the rule applies to, all hosts when empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    pub invalid: InvalidSession,
+    pub steps: Vec<MacroStep>,
+    #[serde(default)]
+    pub inject: Vec<Injection>,
+    /// Send the cookies the macro was given on every request
+    #[serde(default = "default_true")]
+    pub cookies: bool,
+}
+
+/// Values from the last macro run of a rule
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Session {
+    pub variables: HashMap<String, String>,
+    pub cookies: HashMap<String, String>,
+    /// Unix time in milliseconds
+    pub refreshed_at: u128,
+    #[serde(skip)]
+    refreshed: Option<Instant>,
+}
+
+impl HostScoped for SessionRule {
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+}
+
+impl SessionRule {
+    /// Checks the rule and compiles its invalid session pattern
+    fn compile(mut self) -> Result<Self, String> {
+        if self.steps.is_empty() {
+            return Err(format!("Session rule {} has no macro steps", self.name))
+        }
+        if let Some(pattern) = &self.invalid.pattern {
+            self.invalid.regex = Some(Regex::new(pattern).map_err(|e| format!("Invalid pattern in session rule {}: {e}", self.name))?);
+        }
+        for extractor in self.steps.iter().flat_map(|step| step.extract.iter()) {
+            if let ExtractKind::Regex { pattern } = &extractor.kind {
+                Regex::new(pattern).map_err(|e| format!("Invalid pattern for {} in session rule {}: {e}", extractor.name, self.name))?;
+            }
+        }
+        Ok(self)
+    }
+
+    fn is_invalid(&self, status: u16, raw: &str) -> bool {
+        self.invalid.statuses.contains(&status)
+            || self.invalid.regex.as_ref().is_some_and(|regex| regex.is_match(raw))
+    }
+
+    /// Headers to add for this rule's session
+    fn headers(&self, session: &Session) -> Vec<(String, String)> {
+        let mut headers = self.inject.iter()
+            .filter_map(|injection| match environments::expand(&injection.value, &session.variables) {
+                Ok(value) => Some((injection.header.clone(), value)),
+                Err(e) => {
+                    error!("Skipping {} injection of session rule {}: {e}", injection.header, self.name);
+                    None
+                },
+            })
+            .collect::<Vec<(String, String)>>();
+
+        if self.cookies && !session.cookies.is_empty() {
+            headers.push(("Cookie".to_string(), cookie_header(&session.cookies)));
+        }
+        headers
+    }
+}
+
+/// Sets headers on a raw request, replacing any already there. Cookies are merged into an existing
+/// Cookie header, overriding ones with the same name
+pub fn apply_headers(raw: &str, headers: &[(String, String)]) -> String {
+    let Some((head, body)) = raw.split_once("\r\n\r\n") else {
+        return raw.to_string()
+    };
+
+    let mut lines = head.split("\r\n").map(str::to_string).collect::<Vec<String>>();
+    for (name, value) in headers {
+        let existing = lines.iter().skip(1).position(|line| line.split_once(":").is_some_and(|(key, _)| key.trim().eq_ignore_ascii_case(name))).map(|i| i + 1);
+        let value = match (name.eq_ignore_ascii_case("cookie"), existing) {
+            (true, Some(i)) => merge_cookie_header(lines[i].split_once(":").map(|(_, cookies)| cookies).unwrap_or(""), value),
+            _ => value.clone(),
+        };
+
+        match existing {
+            Some(i) => lines[i] = format!("{name}: {value}"),
+            None => lines.push(format!("{name}: {value}")),
+        }
+    }
+
+    format!("{}\r\n\r\n{}", lines.join("\r\n"), body)
+}
+
+/// Headers carrying the current session for a host, empty until its rule's macro has run
+pub async fn headers(state: &AppState, host: &str) -> Vec<(String, String)> {
+    let rules = state.session_rules.lock().await;
+    let Some(rule) = select_scoped(&rules, host) else {
+        return Vec::new()
+    };
+    match state.sessions.lock().await.get(&rule.name) {
+        Some(session) => rule.headers(session),
+        None => Vec::new(),
+    }
+}
+
+/// Raw request with the current session for its host injected
+pub async fn inject(state: &AppState, host: &str, raw: &str) -> String {
+    let headers = headers(state, host).await;
+    if headers.is_empty() {
+        return raw.to_string()
+    }
+    apply_headers(raw, &headers)
+}
+
+fn json_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.trim_start_matches('$')
+        .split(['.', '[', ']'])
+        .filter(|segment| !segment.is_empty())
+        .try_fold(value, |value, segment| match value {
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+            _ => value.get(segment),
+        })
+}
+
+fn extract(extractor: &Extractor, raw: &str) -> Option<String> {
+    match &extractor.kind {
+        ExtractKind::Regex { pattern } => {
+            let captures = Regex::new(pattern).ok()?.captures(raw)?;
+            captures.get(1).or(captures.get(0)).map(|m| m.as_str().to_string())
+        },
+        ExtractKind::Json { path } => {
+            let body = raw.split_once("\r\n\r\n").map(|(_, body)| body)?;
+            let json = serde_json::from_str::<Value>(body).ok()?;
+            match json_path(&json, path)? {
+                Value::String(value) => Some(value.clone()),
+                value => Some(value.to_string()),
+            }
+        },
+    }
+}
+
+/// Runs the rule's macro from a clean session
+async fn run_macro(state: &AppState, rule: &SessionRule) -> Result<Session, SendError> {
+    let mut session = Session::default();
+
+    for step in &rule.steps {
+        let raw = environments::expand(&step.request, &session.variables).map_err(SendError::InvalidRequest)?;
+        let raw = match session.cookies.is_empty() {
+            true => raw,
+            false => apply_headers(&raw, &[("Cookie".to_string(), cookie_header(&session.cookies))]),
+        };
+        let target = match &step.target {
+            Some(target) => target.clone(),
+            None => target_from_host(client::build_request(&raw)?.1)?,
+        };
+
+        let res = send_once(state, &raw, &target, None).await?;
+        for (_, value) in res.headers.iter().filter(|(name, _)| name.eq_ignore_ascii_case("set-cookie")) {
+            if let Some((name, value)) = value.split(";").next().and_then(|cookie| cookie.split_once("=")) {
+                session.cookies.insert(name.trim().to_string(), value.trim().to_string());
+            }
+        }
+        for extractor in &step.extract {
+            let value = extract(extractor, &res.raw)
+                .ok_or(SendError::Http(format!("Could not extract {} from {}", extractor.name, res.url)))?;
+            session.variables.insert(extractor.name.clone(), value);
+        }
+    }
+
+    session.refreshed = Some(Instant::now());
+    session.refreshed_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    Ok(session)
+}
+
+/// Runs the rule's macro and stores the session it gives. Only one macro per rule runs at a time,
+/// `sessions` is only locked to store the result so traffic for other hosts carries on meanwhile.
+/// With `sent`, a session refreshed after it is kept instead of logging in again
+async fn refresh(state: &AppState, rule: &SessionRule, sent: Option<Instant>) -> Result<Session, SendError> {
+    let guard = state.session_refreshes.lock().await.entry(rule.name.clone()).or_default().clone();
+    let _running = guard.lock().await;
+
+    let current = state.sessions.lock().await.get(&rule.name).cloned();
+    if let Some(current) = current.filter(|session| sent.is_some_and(|sent| session.refreshed.is_some_and(|refreshed| refreshed > sent))) {
+        return Ok(current)
+    }
+
+    let session = match timeout(MACRO_TIMEOUT, run_macro(state, rule)).await {
+        Ok(session) => session?,
+        Err(_) => return Err(SendError::Timeout(format!("Macro did not finish within {}s", MACRO_TIMEOUT.as_secs()))),
+    };
+    state.sessions.lock().await.insert(rule.name.clone(), session.clone());
+    Ok(session)
+}
+
+/// Checks a response against the host's session rule and logs in again when it shows the session expired.
+/// Returns whether the request should be retried, which is also the case when another request already
+/// refreshed the session after `sent`
+pub async fn refresh_if_invalid(state: &AppState, host: &str, status: u16, raw: &str, sent: Instant) -> bool {
+    let rule = match select_scoped(&state.session_rules.lock().await, host) {
+        Some(rule) if rule.is_invalid(status, raw) => rule.clone(),
+        _ => return false,
+    };
+
+    info!("Session for {host} expired, running macro of session rule {}", rule.name);
+    match refresh(state, &rule, Some(sent)).await {
+        Ok(_) => true,
+        Err(e) => {
+            error!("Macro of session rule {} failed: {e}", rule.name);
+            false
+        },
+    }
+}
+
+#[tauri::command]
+pub async fn get_session_rules(state: State<'_, Arc<AppState>>) -> Result<Vec<SessionRule>, String> {
+    Ok(state.session_rules.lock().await.clone())
+}
+
+#[tauri::command]
+pub async fn set_session_rules(state: State<'_, Arc<AppState>>, rules: Vec<SessionRule>) -> Result<(), String> {
+    let rules = rules.into_iter().map(SessionRule::compile).collect::<Result<Vec<SessionRule>, String>>()?;
+
+    info!("Updated session rules: {}", rules.len());
+    *state.session_rules.lock().await = rules;
+    Ok(())
+}
+
+/// Current session of every rule that has logged in
+#[tauri::command]
+pub async fn get_sessions(state: State<'_, Arc<AppState>>) -> Result<HashMap<String, Session>, String> {
+    Ok(state.sessions.lock().await.clone())
+}
+
+/// Runs a rule's macro now instead of waiting for an invalid session
+#[tauri::command]
+pub async fn run_session_macro(state: State<'_, Arc<AppState>>, name: String) -> Result<Session, String> {
+    let rule = state.session_rules.lock().await.iter().find(|rule| rule.name == name).cloned()
+        .ok_or(format!("No session rule named {name}"))?;
+    refresh(&state, &rule, None).await.map_err(|e| e.to_string())
+}
@@ -0,0 +1,409 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD, prelude::BASE64_STANDARD};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256, Sha512};
+use tauri::State;
+
+use crate::{AppState, client::{self, Target}, encode_jwt, network::{HostScoped, select_scoped}, session::apply_headers};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HmacAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureEncoding {
+    Hex,
+    Base64,
+}
+
+/// Piece of the request that goes into a generic HMAC signature
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CanonicalPart {
+    Method,
+    /// Path without the query
+    Path,
+    Query,
+    Host,
+    Body,
+    /// Hex SHA256 of the body
+    BodySha256,
+    /// Value of a request header, empty when missing
+    Header { name: String },
+    /// Unix time in seconds, the same value sent in `timestamp_header`
+    Timestamp,
+    Literal { value: String },
+}
+
+fn default_separator() -> String {
+    "\n".to_string()
+}
+
+fn default_authorization() -> String {
+    "Authorization".to_string()
+}
+
+fn default_bearer() -> String {
+    "Bearer ".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Signer {
+    AwsSigV4 {
+        access_key: String,
+        secret_key: String,
+        #[serde(default)]
+        session_token: Option<String>,
+        region: String,
+        service: String,
+    },
+    /// HMAC over the chosen parts joined by `separator`, sent in `header` after `prefix`
+    Hmac {
+        key: String,
+        algorithm: HmacAlgorithm,
+        parts: Vec<CanonicalPart>,
+        #[serde(default = "default_separator")]
+        separator: String,
+        header: String,
+        #[serde(default)]
+        prefix: String,
+        encoding: SignatureEncoding,
+        #[serde(default)]
+        timestamp_header: Option<String>,
+    },
+    /// Re-signs the JWT already in `header` with `key`, keeping its header and payload as edited
+    Jwt {
+        key: String,
+        #[serde(default = "default_authorization")]
+        header: String,
+        #[serde(default = "default_bearer")]
+        prefix: String,
+        /// HMAC alg to sign with instead of the one in the token's header
+        #[serde(default)]
+        algorithm: Option<String>,
+    },
+}
+
+/// JWT algs a shared secret can sign with
+const JWT_HMAC_ALGORITHMS: [&str; 3] = ["HS256", "HS384", "HS512"];
+
+/// Signs requests to matching hosts as the last step before they are sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigningProfile {
+    pub name: String,
+    pub enabled: bool,
+    /// Host globs the profile applies to, all hosts when empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    pub signer: Signer,
+}
+
+/// The parts of a request a signature is computed over
+pub struct SignInput<'a> {
+    pub method: &'a str,
+    /// Host header value, or the target authority without one
+    pub host: &'a str,
+    pub path: &'a str,
+    pub query: &'a str,
+    pub headers: Vec<(String, String)>,
+    pub body: &'a [u8],
+}
+
+impl SignInput<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// RFC 3986 encoding of everything but the unreserved characters, `/` is kept unless `encode_slash`
+fn uri_encode(text: &str, encode_slash: bool) -> String {
+    text.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        b'/' if !encode_slash => "/".to_string(),
+        _ => format!("%{b:02X}"),
+    }).collect()
+}
+
+/// Undoes percent encoding so already encoded input isn't encoded twice, invalid escapes are kept as is
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Canonical URI: dot segments resolved and every segment encoded, twice for all services but S3
+fn canonical_path(path: &str, service: &str) -> String {
+    let s3 = service == "s3";
+    let mut segments = Vec::new();
+    for segment in path.split('/').skip(1) {
+        match segment {
+            "." if !s3 => {},
+            ".." if !s3 => {
+                segments.pop();
+            },
+            _ => {
+                let encoded = uri_encode(&percent_decode(segment), true);
+                segments.push(if s3 { encoded } else { uri_encode(&encoded, true) });
+            },
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+/// Canonical query: names and values encoded, then sorted by name and value
+fn canonical_query(query: &str) -> String {
+    let mut pairs = query.split("&")
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once("=").unwrap_or((pair, ""));
+            (uri_encode(&percent_decode(key), true), uri_encode(&percent_decode(value), true))
+        })
+        .collect::<Vec<(String, String)>>();
+    pairs.sort();
+    pairs.iter().map(|(key, value)| format!("{key}={value}")).collect::<Vec<String>>().join("&")
+}
+
+fn sigv4(input: &SignInput, access_key: &str, secret_key: &str, session_token: Option<&str>, region: &str, service: &str) -> Vec<(String, String)> {
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let payload_hash = format!("{:x}", Sha256::digest(input.body));
+
+    // Host, content type and the x-amz headers we set are signed, repeated headers as one comma joined value
+    let mut signed = BTreeMap::<String, Vec<String>>::new();
+    for (name, value) in &input.headers {
+        let name = name.to_lowercase();
+        if name == "content-type" || (name.starts_with("x-amz-") && name != "x-amz-date" && name != "x-amz-content-sha256" && name != "x-amz-security-token") {
+            signed.entry(name).or_default().push(value.split_whitespace().collect::<Vec<&str>>().join(" "));
+        }
+    }
+    signed.insert("host".to_string(), vec![input.host.to_string()]);
+    signed.insert("x-amz-content-sha256".to_string(), vec![payload_hash.clone()]);
+    signed.insert("x-amz-date".to_string(), vec![amz_date.clone()]);
+    if let Some(token) = session_token {
+        signed.insert("x-amz-security-token".to_string(), vec![token.to_string()]);
+    }
+
+    let canonical_headers = signed.iter().map(|(name, values)| format!("{name}:{}\n", values.join(","))).collect::<String>();
+    let signed_headers = signed.keys().map(String::as_str).collect::<Vec<&str>>().join(";");
+    let canonical_request = [
+        input.method.to_string(),
+        canonical_path(input.path, service),
+        canonical_query(input.query),
+        canonical_headers,
+        signed_headers.clone(),
+        payload_hash.clone(),
+    ].join("\n");
+
+    let scope = format!("{date}/{region}/{service}/aws4_request");
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{:x}", Sha256::digest(canonical_request.as_bytes()));
+    let key = [date.as_str(), region, service, "aws4_request"].iter()
+        .fold(format!("AWS4{secret_key}").into_bytes(), |key, part| hmac_sha256(&key, part.as_bytes()));
+    let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+    let mut headers = vec![
+        ("X-Amz-Date".to_string(), amz_date),
+        ("X-Amz-Content-Sha256".to_string(), payload_hash),
+    ];
+    if let Some(token) = session_token {
+        headers.push(("X-Amz-Security-Token".to_string(), token.to_string()));
+    }
+    headers.push(("Authorization".to_string(), format!("AWS4-HMAC-SHA256 Credential={access_key}/{scope}, SignedHeaders={signed_headers}, Signature={signature}")));
+    headers
+}
+
+/// Decodes the token's header and payload and signs them again through the Tokenizer's encoder
+fn resign_jwt(input: &SignInput, key: &str, header: &str, prefix: &str, algorithm: Option<&str>) -> Result<Vec<(String, String)>, String> {
+    let Some(token) = input.header(header).and_then(|value| value.strip_prefix(prefix)) else {
+        return Err(format!("No token in the {header} header to re-sign"))
+    };
+    let mut segments = token.trim().split(".");
+    let mut decode = |part: &str| -> Result<String, String> {
+        let bytes = URL_SAFE_NO_PAD.decode(segments.next().unwrap_or("").trim_end_matches('='))
+            .map_err(|e| format!("Invalid JWT {part}: {e}"))?;
+        String::from_utf8(bytes).map_err(|e| format!("Invalid JWT {part}: {e}"))
+    };
+    let (mut jwt_header, payload) = (decode("header")?, decode("payload")?);
+    let mut fields = serde_json::from_str::<Value>(&jwt_header).map_err(|e| format!("Invalid JWT header: {e}"))?;
+    if let Some(algorithm) = algorithm {
+        fields["alg"] = Value::String(algorithm.to_string());
+        jwt_header = fields.to_string();
+    }
+    match fields.get("alg").and_then(Value::as_str) {
+        Some(alg) if JWT_HMAC_ALGORITHMS.contains(&alg) => {},
+        Some(alg) => return Err(format!("Cannot re-sign a JWT with alg {alg}, expected one of {}", JWT_HMAC_ALGORITHMS.join(", "))),
+        None => return Err("Cannot re-sign a JWT without a string alg in its header".to_string()),
+    }
+
+    let result = encode_jwt(jwt_header, payload, key.to_string());
+    if let Some(note) = result.notes.iter().find(|note| note.importance == "error") {
+        return Err(format!("Failed to re-sign JWT: {}", note.note))
+    }
+    if result.signature.is_empty() {
+        return Err("Failed to re-sign JWT: no signature was produced".to_string())
+    }
+    Ok(vec![(header.to_string(), format!("{prefix}{}.{}.{}", result.header, result.payload, result.signature))])
+}
+
+impl Signer {
+    /// Headers carrying the signature, set on the request replacing any already there
+    pub fn sign(&self, input: &SignInput) -> Result<Vec<(String, String)>, String> {
+        match self {
+            Signer::AwsSigV4 { access_key, secret_key, session_token, region, service } => {
+                Ok(sigv4(input, access_key, secret_key, session_token.as_deref(), region, service))
+            },
+            Signer::Hmac { key, algorithm, parts, separator, header, prefix, encoding, timestamp_header } => {
+                let timestamp = Utc::now().timestamp().to_string();
+                let message = parts.iter().map(|part| match part {
+                    CanonicalPart::Method => input.method.to_string(),
+                    CanonicalPart::Path => input.path.to_string(),
+                    CanonicalPart::Query => input.query.to_string(),
+                    CanonicalPart::Host => input.host.to_string(),
+                    CanonicalPart::Body => String::from_utf8_lossy(input.body).to_string(),
+                    CanonicalPart::BodySha256 => format!("{:x}", Sha256::digest(input.body)),
+                    CanonicalPart::Header { name } => input.header(name).unwrap_or("").to_string(),
+                    CanonicalPart::Timestamp => timestamp.clone(),
+                    CanonicalPart::Literal { value } => value.clone(),
+                }).collect::<Vec<String>>().join(separator);
+
+                let mac = match algorithm {
+                    HmacAlgorithm::Sha256 => hmac_sha256(key.as_bytes(), message.as_bytes()),
+                    HmacAlgorithm::Sha512 => hmac_sha512(key.as_bytes(), message.as_bytes()),
+                };
+                let signature = match encoding {
+                    SignatureEncoding::Hex => hex(&mac),
+                    SignatureEncoding::Base64 => BASE64_STANDARD.encode(mac),
+                };
+
+                let mut headers = Vec::new();
+                if let Some(name) = timestamp_header {
+                    headers.push((name.clone(), timestamp));
+                }
+                headers.push((header.clone(), format!("{prefix}{signature}")));
+                Ok(headers)
+            },
+            Signer::Jwt { key, header, prefix, algorithm } => resign_jwt(input, key, header, prefix, algorithm.as_deref()),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let required = match self {
+            Signer::AwsSigV4 { access_key, secret_key, region, service, .. } => {
+                vec![("access key", access_key), ("secret key", secret_key), ("region", region), ("service", service)]
+            },
+            Signer::Hmac { key, parts, header, .. } => {
+                if parts.is_empty() {
+                    return Err("HMAC signer has nothing to sign".to_string())
+                }
+                vec![("key", key), ("header", header)]
+            },
+            Signer::Jwt { key, header, algorithm, .. } => {
+                if let Some(algorithm) = algorithm.as_ref().filter(|algorithm| !JWT_HMAC_ALGORITHMS.contains(&algorithm.as_str())) {
+                    return Err(format!("Unsupported JWT alg {algorithm}, expected one of {}", JWT_HMAC_ALGORITHMS.join(", ")))
+                }
+                vec![("key", key), ("header", header)]
+            },
+        };
+        match required.iter().find(|(_, value)| value.trim().is_empty()) {
+            Some((field, _)) => Err(format!("Signer needs a {field}")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl HostScoped for SigningProfile {
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+}
+
+/// Signer of the profile for `host`, if any
+pub async fn signer(state: &AppState, host: &str) -> Option<Signer> {
+    select_scoped(&state.signing_profiles.lock().await, host).map(|profile| profile.signer.clone())
+}
+
+/// Raw request with the signature of the host's profile set, unchanged when no profile applies
+pub async fn sign_raw(state: &AppState, target: &Target, raw: &str) -> Result<String, String> {
+    let Some(signer) = signer(state, &target.host).await else {
+        return Ok(raw.to_string())
+    };
+
+    let (req, host) = client::build_request(raw).map_err(|e| e.to_string())?;
+    let host = host.unwrap_or(target.authority());
+    let input = SignInput {
+        method: req.method().as_str(),
+        host: &host,
+        path: req.uri().path(),
+        query: req.uri().query().unwrap_or(""),
+        headers: req.headers().iter()
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).to_string()))
+            .collect(),
+        body: raw.split_once("\r\n\r\n").map(|(_, body)| body.as_bytes()).unwrap_or_default(),
+    };
+
+    let headers = signer.sign(&input)?;
+    Ok(apply_headers(raw, &headers))
+}
+
+#[tauri::command]
+pub async fn get_signing_profiles(state: State<'_, Arc<AppState>>) -> Result<Vec<SigningProfile>, String> {
+    Ok(state.signing_profiles.lock().await.clone())
+}
+
+#[tauri::command]
+pub async fn set_signing_profiles(state: State<'_, Arc<AppState>>, profiles: Vec<SigningProfile>) -> Result<(), String> {
+    for profile in &profiles {
+        profile.signer.validate().map_err(|e| format!("Invalid signing profile {}: {e}", profile.name))?;
+    }
+
+    info!("Updated signing profiles: {}", profiles.len());
+    *state.signing_profiles.lock().await = profiles;
+    Ok(())
+}
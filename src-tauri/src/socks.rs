@@ -0,0 +1,135 @@
+use std::{io, net::{Ipv4Addr, Ipv6Addr}};
+
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+async fn reply(stream: &mut TcpStream, code: u8) -> io::Result<()> {
+    // Bound address is never used by clients, so always report 0.0.0.0:0
+    stream.write_all(&[VERSION, code, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await?;
+    stream.flush().await
+}
+
+/// Server side of the handshake. Only unauthenticated CONNECT is supported,
+/// returns the requested target host and port
+pub async fn accept(stream: &mut TcpStream) -> io::Result<(String, u16)> {
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await?;
+    if greeting[0] != VERSION {
+        return Err(invalid(format!("Unsupported SOCKS version {}", greeting[0])))
+    }
+
+    let mut methods = vec![0u8; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        return Err(invalid("SOCKS client does not offer unauthenticated access".to_string()))
+    }
+    stream.write_all(&[VERSION, NO_AUTH]).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[1] != CMD_CONNECT {
+        reply(stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Err(invalid(format!("Unsupported SOCKS command {}", header[1])))
+    }
+
+    let host = match header[3] {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            stream.read_exact(&mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        },
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut domain = vec![0u8; len as usize];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8(domain).map_err(|_| invalid("Invalid SOCKS domain".to_string()))?
+        },
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            stream.read_exact(&mut addr).await?;
+            Ipv6Addr::from(addr).to_string()
+        },
+        atyp => {
+            reply(stream, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(invalid(format!("Unsupported SOCKS address type {atyp}")))
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    reply(stream, REPLY_SUCCEEDED).await?;
+
+    Ok((host, port))
+}
+
+/// Client side of the handshake over an already open connection to a SOCKS5 server.
+/// The target host is always sent as a domain so the server resolves it
+pub async fn connect(stream: &mut TcpStream, host: &str, port: u16, auth: Option<(&str, &str)>) -> io::Result<()> {
+    let method = if auth.is_some() { USER_PASS } else { NO_AUTH };
+    stream.write_all(&[VERSION, 1, method]).await?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[1] != method {
+        return Err(invalid("SOCKS server rejected the authentication method".to_string()))
+    }
+
+    if let Some((username, password)) = auth {
+        if username.len() > 255 || password.len() > 255 {
+            return Err(invalid("SOCKS credentials are too long".to_string()))
+        }
+        let mut msg = vec![0x01, username.len() as u8];
+        msg.extend_from_slice(username.as_bytes());
+        msg.push(password.len() as u8);
+        msg.extend_from_slice(password.as_bytes());
+        stream.write_all(&msg).await?;
+
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await?;
+        if status[1] != 0x00 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS authentication failed"))
+        }
+    }
+
+    if host.len() > 255 {
+        return Err(invalid(format!("Host too long for SOCKS: {host}")))
+    }
+    let mut request = vec![VERSION, CMD_CONNECT, 0x00, ATYP_DOMAIN, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[1] != REPLY_SUCCEEDED {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+            format!("SOCKS server refused connection to {host}:{port} (reply {})", header[1])))
+    }
+
+    // Skip the bound address
+    let addr_len = match header[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        atyp => return Err(invalid(format!("Unsupported SOCKS address type {atyp}"))),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(())
+}
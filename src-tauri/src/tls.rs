@@ -0,0 +1,266 @@
+use std::{fs, io, sync::{Arc, LazyLock}};
+
+use log::info;
+use reqwest::{ClientBuilder, Url, header::{HOST, HeaderMap, HeaderValue}};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tokio::net::lookup_host;
+use tokio_rustls::rustls::{
+    self, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme, SupportedProtocolVersion,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, aws_lc_rs, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime, pem::PemObject},
+};
+
+use crate::{AppState, network::host_matches};
+
+fn tls_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// rustls only implements TLS 1.2 and 1.3
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn protocol(&self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => &rustls::version::TLS12,
+            TlsVersion::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+pub enum ClientCert {
+    Pem { cert_path: String, key_path: String },
+    Pkcs12 { path: String, password: String },
+}
+
+impl ClientCert {
+    fn load(&self) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+        match self {
+            ClientCert::Pem { cert_path, key_path } => {
+                let certs = CertificateDer::pem_file_iter(cert_path)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| tls_error(format!("Failed to read client certificate {cert_path}: {e}")))?;
+                let key = PrivateKeyDer::from_pem_file(key_path)
+                    .map_err(|e| tls_error(format!("Failed to read client key {key_path}: {e}")))?;
+                Ok((certs, key))
+            },
+            ClientCert::Pkcs12 { path, password } => {
+                let data = fs::read(path)?;
+                let keystore = p12_keystore::KeyStore::from_pkcs12(&data, password)
+                    .map_err(|e| tls_error(format!("Failed to read PKCS#12 {path}: {e}")))?;
+                let Some((_, chain)) = keystore.private_key_chain() else {
+                    return Err(tls_error(format!("No private key in PKCS#12 {path}")))
+                };
+
+                let certs = chain.chain().iter().map(|cert| CertificateDer::from(cert.as_der().to_vec())).collect();
+                let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(chain.key().to_vec()));
+                Ok((certs, key))
+            }
+        }
+    }
+}
+
+/// How we talk TLS to upstream servers matching `host`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsProfile {
+    pub name: String,
+    /// Host glob, e.g. `*.staging.example.com`
+    pub host: String,
+    pub enabled: bool,
+    #[serde(default)]
+    pub client_cert: Option<ClientCert>,
+    #[serde(default)]
+    pub skip_verify: bool,
+    /// PEM files trusted in addition to the bundled web roots
+    #[serde(default)]
+    pub root_cas: Vec<String>,
+    #[serde(default)]
+    pub min_version: Option<TlsVersion>,
+    #[serde(default)]
+    pub max_version: Option<TlsVersion>,
+    /// rustls suite names, e.g. `TLS13_AES_128_GCM_SHA256`. Empty allows all
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// Server name sent instead of the target host
+    #[serde(default)]
+    pub sni: Option<String>,
+    /// Built when the profile is set, so connections don't read its files again
+    #[serde(skip)]
+    config: Option<Arc<ClientConfig>>,
+}
+
+/// Config for hosts without a profile, built on first use
+static DEFAULT_CONFIG: LazyLock<io::Result<Arc<ClientConfig>>> = LazyLock::new(|| TlsProfile::default().client_config().map(Arc::new));
+
+/// Used for hosts without a profile: bundled web roots and default versions
+impl Default for TlsProfile {
+    fn default() -> Self {
+        TlsProfile {
+            name: "default".to_string(),
+            host: "*".to_string(),
+            enabled: true,
+            client_cert: None,
+            skip_verify: false,
+            root_cas: Vec::new(),
+            min_version: None,
+            max_version: None,
+            cipher_suites: Vec::new(),
+            sni: None,
+            config: None,
+        }
+    }
+}
+
+impl TlsProfile {
+    /// Checks the profile and builds its config
+    fn compile(mut self) -> io::Result<Self> {
+        self.config = Some(Arc::new(self.client_config()?));
+        Ok(self)
+    }
+
+    fn client_config(&self) -> io::Result<ClientConfig> {
+        let mut provider = aws_lc_rs::default_provider();
+        if !self.cipher_suites.is_empty() {
+            provider.cipher_suites.retain(|suite| {
+                let name = format!("{:?}", suite.suite());
+                self.cipher_suites.iter().any(|wanted| wanted.eq_ignore_ascii_case(&name))
+            });
+            if provider.cipher_suites.is_empty() {
+                return Err(tls_error(format!("Profile {} allows no supported cipher suites", self.name)))
+            }
+        }
+        let provider = Arc::new(provider);
+
+        let versions = [TlsVersion::Tls12, TlsVersion::Tls13].into_iter()
+            .filter(|version| self.min_version.map_or(true, |min| *version >= min))
+            .filter(|version| self.max_version.map_or(true, |max| *version <= max))
+            .map(|version| version.protocol())
+            .collect::<Vec<&'static SupportedProtocolVersion>>();
+        if versions.is_empty() {
+            return Err(tls_error(format!("Profile {} allows no TLS versions", self.name)))
+        }
+
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&versions)
+            .map_err(|e| tls_error(format!("Invalid TLS settings in profile {}: {e}", self.name)))?;
+
+        let builder = if self.skip_verify {
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(NoVerifier(provider)))
+        } else {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            for path in &self.root_cas {
+                let certs = CertificateDer::pem_file_iter(path)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| tls_error(format!("Failed to read root CA {path}: {e}")))?;
+                roots.add_parsable_certificates(certs);
+            }
+            builder.with_root_certificates(roots)
+        };
+
+        let mut config = match &self.client_cert {
+            Some(client_cert) => {
+                let (certs, key) = client_cert.load()?;
+                builder.with_client_auth_cert(certs, key)
+                    .map_err(|e| tls_error(format!("Invalid client certificate in profile {}: {e}", self.name)))?
+            },
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(config)
+    }
+
+    /// reqwest always sends the URL host as SNI, so an override requests the SNI name
+    /// and pins it to the real target's addresses. Host is set to the original authority
+    /// so the server still sees the target it was asked for
+    async fn apply_sni(&self, builder: ClientBuilder, url: &mut Url) -> io::Result<ClientBuilder> {
+        let Some(sni) = &self.sni else {
+            return Ok(builder)
+        };
+        let Some(host) = url.host_str().map(str::to_string) else {
+            return Ok(builder)
+        };
+
+        let port = url.port_or_known_default().unwrap_or(443);
+        let addrs = lookup_host((host.as_str(), port)).await?.collect::<Vec<_>>();
+        let authority = match url.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.clone(),
+        };
+        let host_header = HeaderValue::from_str(&authority).map_err(|e| tls_error(format!("Invalid host {authority}: {e}")))?;
+        url.set_host(Some(sni)).map_err(|e| tls_error(format!("Invalid SNI {sni}: {e}")))?;
+
+        Ok(builder
+            .resolve_to_addrs(sni, &addrs)
+            .default_headers(HeaderMap::from_iter([(HOST, host_header)])))
+    }
+}
+
+/// Accepts any server certificate but still checks handshake signatures
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+pub fn select_profile<'a>(profiles: &'a [TlsProfile], host: &str) -> Option<&'a TlsProfile> {
+    profiles.iter().find(|profile| profile.enabled && host_matches(&profile.host, host))
+}
+
+/// Config built when `profile` was set, or the default one for hosts without a profile
+pub fn profile_config(profile: Option<&TlsProfile>) -> io::Result<Arc<ClientConfig>> {
+    match profile.and_then(|profile| profile.config.as_ref()) {
+        Some(config) => Ok(config.clone()),
+        None => DEFAULT_CONFIG.as_ref().map(Arc::clone).map_err(|e| tls_error(e.to_string())),
+    }
+}
+
+/// Applies the TLS profile matching the URL's host, rewriting the URL for SNI overrides
+pub async fn apply_profile(profiles: &[TlsProfile], builder: ClientBuilder, url: &mut Url) -> io::Result<ClientBuilder> {
+    let Some(profile) = url.host_str().and_then(|host| select_profile(profiles, host)) else {
+        return Ok(builder)
+    };
+
+    let builder = builder.use_preconfigured_tls(ClientConfig::clone(&*profile_config(Some(profile))?));
+    profile.apply_sni(builder, url).await
+}
+
+#[tauri::command]
+pub async fn get_tls_profiles(state: State<'_, Arc<AppState>>) -> Result<Vec<TlsProfile>, String> {
+    Ok(state.tls_profiles.lock().await.clone())
+}
+
+#[tauri::command]
+pub async fn set_tls_profiles(state: State<'_, Arc<AppState>>, profiles: Vec<TlsProfile>) -> Result<(), String> {
+    let profiles = profiles.into_iter().map(TlsProfile::compile).collect::<io::Result<Vec<TlsProfile>>>().map_err(|e| e.to_string())?;
+
+    info!("Updated TLS profiles: {}", profiles.len());
+    *state.tls_profiles.lock().await = profiles;
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use std::{io, sync::Arc};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use log::info;
+use reqwest::{ClientBuilder, Proxy, Url};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+
+use crate::{AppState, network::host_matches, socks};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamKind {
+    Http,
+    Socks5,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamProxy {
+    pub name: String,
+    pub kind: UpstreamKind,
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl UpstreamProxy {
+    fn url(&self) -> io::Result<Url> {
+        // socks5h so the upstream resolves the target, same as an HTTP CONNECT proxy would
+        let scheme = match self.kind {
+            UpstreamKind::Http => "http",
+            UpstreamKind::Socks5 => "socks5h",
+        };
+        let mut url = Url::parse(&format!("{scheme}://{}:{}", self.host, self.port)).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid upstream proxy {}: {e}", self.name))
+        })?;
+
+        if let Some(username) = &self.username {
+            let _ = url.set_username(username);
+            let _ = url.set_password(self.password.as_deref());
+        }
+
+        Ok(url)
+    }
+
+    async fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let mut request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+        if let Some(username) = &self.username {
+            let credentials = BASE64_STANDARD.encode(format!("{}:{}", username, self.password.as_deref().unwrap_or("")));
+            request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte so nothing past the response head is consumed from the tunnel
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() > 8192 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Upstream CONNECT response too large"))
+            }
+            head.push(stream.read_u8().await?);
+        }
+
+        let head = String::from_utf8_lossy(&head);
+        let status = head.split_whitespace().nth(1).unwrap_or("");
+        if status != "200" {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+                format!("Upstream {} refused CONNECT to {host}:{port}: {}", self.name, head.lines().next().unwrap_or(""))))
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamRule {
+    /// Host glob, e.g. `*.corp.local`
+    pub pattern: String,
+    /// Name of the upstream proxy to use, `None` connects directly
+    #[serde(default)]
+    pub proxy: Option<String>,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpstreamConfig {
+    pub proxies: Vec<UpstreamProxy>,
+    pub rules: Vec<UpstreamRule>,
+}
+
+impl UpstreamConfig {
+    /// First enabled rule matching the host wins, no match means a direct connection
+    pub fn select(&self, host: &str) -> Option<&UpstreamProxy> {
+        let rule = self.rules.iter().find(|rule| rule.enabled && host_matches(&rule.pattern, host))?;
+        let name = rule.proxy.as_ref()?;
+        self.proxies.iter().find(|proxy| &proxy.name == name)
+    }
+
+    /// Opens a TCP connection to the target, tunnelled through the upstream selected for the host
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let Some(proxy) = self.select(host) else {
+            return TcpStream::connect((host, port)).await;
+        };
+
+        let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).await?;
+        match proxy.kind {
+            UpstreamKind::Http => proxy.http_connect(&mut stream, host, port).await?,
+            UpstreamKind::Socks5 => {
+                let auth = proxy.username.as_deref().map(|username| (username, proxy.password.as_deref().unwrap_or("")));
+                socks::connect(&mut stream, host, port, auth).await?
+            }
+        }
+
+        Ok(stream)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        for proxy in &self.proxies {
+            proxy.url()?;
+        }
+
+        for rule in &self.rules {
+            if let Some(name) = &rule.proxy {
+                if !self.proxies.iter().any(|proxy| &proxy.name == name) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        format!("Rule {} references unknown upstream {name}", rule.pattern)))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn apply(&self, builder: ClientBuilder) -> ClientBuilder {
+        if self.rules.is_empty() {
+            return builder;
+        }
+
+        let config = self.clone();
+        builder.proxy(Proxy::custom(move |url| {
+            let host = url.host_str()?;
+            config.select(host)?.url().ok()
+        }))
+    }
+}
+
+#[tauri::command]
+pub async fn get_upstream_config(state: State<'_, Arc<AppState>>) -> Result<UpstreamConfig, String> {
+    Ok(state.upstream.lock().await.clone())
+}
+
+#[tauri::command]
+pub async fn set_upstream_config(state: State<'_, Arc<AppState>>, config: UpstreamConfig) -> Result<(), String> {
+    config.validate().map_err(|e| e.to_string())?;
+
+    info!("Updated upstream config: {} proxies, {} rules", config.proxies.len(), config.rules.len());
+    *state.upstream.lock().await = config;
+
+    Ok(())
+}
@@ -21,6 +21,7 @@ export type HttpReqRecv = {
     body: string,
     id: string,
     raw: string,
+    rules: string[],
 };
 
 export type HttpResRecv = {
@@ -29,6 +30,17 @@ export type HttpResRecv = {
     headers: [],
     body: string,
     raw: string,
+    rules: string[],
+};
+
+export type PassthroughRecv = {
+    id: string,
+    host: string,
+    port: number,
+    bytes_sent: number,
+    bytes_received: number,
+    duration_ms: number,
+    error: string | null,
 };
 
 export type Request = {
@@ -70,6 +82,22 @@ export function parse_request_from_payload(payload: HttpReqRecv): Request {
     };
 }
 
+export function parse_passthrough_from_payload(payload: PassthroughRecv): Request {
+    return {
+        id: payload.id,
+        uuid: payload.id,
+        headers: [],
+        path: "",
+        method: "TUNNEL",
+        body: "",
+        destination: `${payload.host}:${payload.port}`,
+        state: payload.error ? "Failed" : "Passthrough",
+        status: `${payload.duration_ms} ms`,
+        length: payload.bytes_sent + payload.bytes_received,
+        raw: ""
+    };
+}
+
 export function parse_response_from_payload(payload: HttpResRecv): Response {
     console.log(payload);
     return {
@@ -82,13 +110,120 @@ export function parse_response_from_payload(payload: HttpResRecv): Response {
     };
 }
 
-export function forward_request(current_request, text) {
+export type Timings = {
+    dns_ms: number | null,
+    connect_ms: number,
+    tls_ms: number | null,
+    ttfb_ms: number,
+    total_ms: number,
+};
+
+export type RepeaterRes = HttpResRecv & {
+    url: string,
+    size: number,
+    timings: Timings,
+    hops: { request: string, response: RepeaterRes }[],
+};
+
+export type RepeaterError = {
+    id: string,
+    kind: "invalid_request" | "dns" | "connect" | "tls" | "timeout" | "http",
+    message: string,
+};
+
+export type Target = {
+    scheme: "http" | "https",
+    host: string,
+    port: number,
+};
+
+export type RepeaterEntry = {
+    id: string,
+    tab: string,
+    sent_at: number,
+    request: string,
+    template: string | null,
+    target: Target | null,
+    sni: string | null,
+    raw_mode: boolean,
+    follow_redirects: number,
+    use_cookie_jar: boolean,
+    response: RepeaterRes | null,
+    error: RepeaterError | null,
+};
+
+export type SendOptions = {
+    id?: string,
+    tab?: string,
+    target?: Target,
+    sni?: string,
+    raw_mode?: boolean,
+    follow_redirects?: number,
+    environment?: string,
+    use_cookie_jar?: boolean,
+};
+
+export async function forward_request(current_request, text, options: SendOptions = {}): Promise<RepeaterRes | undefined> {
     if (current_request) {
+        const target = options.target ?? null;
+        const sni = options.sni || null;
+        const environment = options.environment || null;
+        // Raw mode sends the editor bytes untouched, line endings included
+        if (options.raw_mode) {
+            return await invoke<RepeaterRes>("send_raw_request", {id: options.id, tab: options.tab, raw: text, target, sni, environment});
+        }
         let parsed = fix_whitespaces(text);
-        invoke("send_request", {raw: parsed});
+        return await invoke<RepeaterRes>("send_request", {
+            id: options.id,
+            tab: options.tab,
+            raw: parsed,
+            options: {target, sni, environment, follow_redirects: options.follow_redirects ?? 0, use_cookie_jar: options.use_cookie_jar ?? false},
+        });
     }
 }
 
+export type Environments = {
+    active: string | null,
+    sets: { [name: string]: { [variable: string]: string } },
+};
+
+export async function get_environments(): Promise<Environments> {
+    return await invoke<Environments>("get_environments");
+}
+
+export async function set_environments(environments: Environments) {
+    await invoke("set_environments", {environments});
+}
+
+export type Cookie = {
+    name: string,
+    value: string,
+    domain: string,
+    host_only: boolean,
+    path: string,
+    secure: boolean,
+    http_only: boolean,
+    expires: number | null,
+};
+
+export async function get_cookies(): Promise<Cookie[]> {
+    return await invoke<Cookie[]>("get_cookies");
+}
+
+export async function set_cookies(cookies: Cookie[]) {
+    await invoke("set_cookies", {cookies});
+}
+
+export type Language = "curl" | "python" | "javascript" | "go" | "rust";
+
+export async function generate_code(raw: string, language: Language, target?: Target): Promise<string> {
+    return await invoke<string>("generate_code", {raw: fix_whitespaces(raw), language, target: target ?? null});
+}
+
+export async function from_curl(cmd: string): Promise<{ raw: string, target: Target }> {
+    return await invoke<{ raw: string, target: Target }>("from_curl", {cmd});
+}
+
 export function fix_whitespaces(raw: string) {
     let normalized = raw.replace(/\r\n|\r/g, "\n");
     return normalized.replace(/\n/g, "\r\n");
@@ -119,4 +254,65 @@ export function construct_response_packet(response: Response): string {
     text += "\r\n";
     text += response.body;
     return text;
-}
\ No newline at end of file
+}
+export type DiffSource =
+    | { source: "flow", id: string }
+    | { source: "repeater", id: string }
+    | { source: "raw", raw: string };
+
+export type DiffOp = { tag: "equal" | "delete" | "insert", text: string };
+
+export type FlowDiff = {
+    headers: { lines: DiffOp[], words: DiffOp[] },
+    body: { lines: DiffOp[], words: DiffOp[] },
+    similarity: number,
+};
+
+export async function diff_flows(a: DiffSource, b: DiffSource, options?: { ignore_dates?: boolean, ignore_csrf?: boolean, ignore_patterns?: string[] }): Promise<FlowDiff> {
+    return await invoke<FlowDiff>("diff_flows", {a, b, options: options ?? null});
+}
+
+export type RaceMode = "http1" | "http2";
+
+export type RaceReport = {
+    id: string,
+    mode: RaceMode,
+    spread_us: number,
+    results: {
+        index: number,
+        request: string,
+        offset_us: number,
+        response: RepeaterRes | null,
+        error: { kind: RepeaterError["kind"], message: string } | null,
+    }[],
+};
+
+export async function send_race(requests: string[], mode: RaceMode, options: { copies?: number, target?: Target, sni?: string, environment?: string, use_cookie_jar?: boolean } = {}): Promise<RaceReport> {
+    return await invoke<RaceReport>("send_race", {
+        requests: requests.map(fix_whitespaces),
+        mode,
+        options: {
+            copies: options.copies ?? null,
+            target: options.target ?? null,
+            sni: options.sni || null,
+            environment: options.environment || null,
+            use_cookie_jar: options.use_cookie_jar ?? false,
+        },
+    });
+}
+
+export type Matcher = (
+    | { type: "status", codes: number[] }
+    | { type: "body", pattern: string, regex?: boolean }
+    | { type: "header", name: string, pattern: string }
+    | { type: "redirect", pattern: string }
+    | { type: "length", min?: number, max?: number }
+    | { type: "timing", delta_ms: number }
+) & { negate?: boolean };
+
+export type Criteria = {
+    success: Matcher[],
+    failure: Matcher[],
+};
+
+export type Outcome = "success" | "failure" | "unknown";